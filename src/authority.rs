@@ -0,0 +1,399 @@
+use crate::dns_hdr::{RData, RRType};
+use crate::name::DomainName;
+use crate::zone::{Record, ZoneError, ZoneRecords};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Lookup<'a> {
+    // the RRset of the asked type at the name
+    Answer(Vec<&'a Record>),
+    // the name is an alias, the CNAME record to follow
+    Cname(&'a Record),
+    // the name exists, but has no records of the asked type
+    NoData,
+    NxDomain,
+    // the name is at or below a zone cut: the NS RRset of the cut and the
+    // addresses of those name servers we hold
+    Referral {
+        cut: &'a DomainName,
+        ns: Vec<&'a Record>,
+        glue: Vec<(&'a DomainName, &'a Record)>,
+    },
+}
+
+/*
+The authoritative data of one zone: everything from the apex down to, and
+including, the NS records of delegated subzones and their glue.
+*/
+#[derive(Debug)]
+pub struct Zone {
+    origin: DomainName,
+    records: HashMap<DomainName, Vec<Record>>,
+    // every name that exists in the zone, empty non-terminals included
+    names: HashSet<DomainName>,
+}
+
+impl Zone {
+    pub fn new(origin: DomainName, records: ZoneRecords) -> Result<Self, ZoneError> {
+        let invalid = |msg: String| ZoneError::Invalid {
+            origin: origin.to_string(),
+            msg,
+        };
+
+        let mut by_name: HashMap<DomainName, Vec<Record>> = HashMap::new();
+        for (name, record) in records {
+            if !name.is_subdomain_of(&origin) {
+                return Err(invalid(format!("{name} is outside the zone")));
+            }
+            by_name.entry(name).or_default().push(record);
+        }
+
+        let soa = by_name.get(&origin).map_or(0, |records| {
+            records
+                .iter()
+                .filter(|r| r.rtype == RRType::SOA as u16)
+                .count()
+        });
+        if soa != 1 {
+            return Err(invalid(format!(
+                "the apex has {soa} SOA records, expected 1"
+            )));
+        }
+
+        let mut names = HashSet::new();
+        for name in by_name.keys() {
+            for n in name.ancestors() {
+                if n == origin || !names.insert(n) {
+                    break;
+                }
+            }
+        }
+        names.insert(origin.clone());
+
+        Ok(Zone {
+            origin,
+            records: by_name,
+            names,
+        })
+    }
+
+    pub fn origin(&self) -> &DomainName {
+        &self.origin
+    }
+
+    pub fn soa(&self) -> &Record {
+        self.rrset(&self.origin, RRType::SOA as u16)[0]
+    }
+
+    fn rrset(&self, name: &DomainName, rtype: u16) -> Vec<&Record> {
+        self.records.get(name).map_or(vec![], |records| {
+            records.iter().filter(|r| r.rtype == rtype).collect()
+        })
+    }
+
+    // name must be in the zone
+    pub fn lookup(&self, name: &DomainName, qtype: u16) -> Lookup<'_> {
+        // the topmost zone cut between the apex and the name wins, whatever
+        // is below it belongs to the child zone (RFC 1034 4.3.2)
+        let below_apex = name
+            .ancestors()
+            .take_while(|n| *n != self.origin)
+            .collect::<Vec<_>>();
+        let cut = below_apex.iter().rev().find_map(|n| {
+            let (cut, _) = self.records.get_key_value(n)?;
+            let ns = self.rrset(cut, RRType::NS as u16);
+            (!ns.is_empty()).then_some((cut, ns))
+        });
+
+        if let Some((cut, ns)) = cut {
+            let glue = ns
+                .iter()
+                .filter_map(|r| match r.rdata() {
+                    RData::NS(target) => self
+                        .records
+                        .get_key_value(&DomainName::from_labels(&target)),
+                    _ => None,
+                })
+                .flat_map(|(target, records)| {
+                    records
+                        .iter()
+                        .filter(|r| r.rtype == RRType::A as u16 || r.rtype == RRType::AAAA as u16)
+                        .map(move |r| (target, r))
+                })
+                .collect();
+
+            return Lookup::Referral { cut, ns, glue };
+        }
+
+        // a name that does not exist may still be covered by a wildcard at
+        // its closest encloser, the longest ancestor that exists (RFC 4592 3.3)
+        let name = match self.names.get(name) {
+            Some(name) => name,
+            None => {
+                let encloser = name
+                    .ancestors()
+                    .find(|n| self.names.contains(n))
+                    .expect("the apex exists");
+                match self.names.get(&encloser.child(b"*")) {
+                    Some(source) => source,
+                    None => return Lookup::NxDomain,
+                }
+            }
+        };
+
+        let rrset = self.rrset(name, qtype);
+        let cname = self.rrset(name, RRType::CNAME as u16);
+        match cname.first() {
+            Some(cname) if rrset.is_empty() => Lookup::Cname(cname),
+            _ if rrset.is_empty() => Lookup::NoData,
+            _ => Lookup::Answer(rrset),
+        }
+    }
+}
+
+// The zones we are authoritative for, by origin
+#[derive(Debug, Default)]
+pub struct Zones {
+    zones: HashMap<DomainName, Zone>,
+}
+
+impl Zones {
+    // Replaces any zone with the same origin
+    pub fn add(&mut self, zone: Zone) {
+        self.zones.insert(zone.origin.clone(), zone);
+    }
+
+    // The zone with the longest origin that name is in
+    pub fn find(&self, name: &DomainName) -> Option<&Zone> {
+        name.ancestors().find_map(|n| self.zones.get(&n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+    use crate::zone;
+    use std::net::Ipv4Addr;
+
+    const EXAMPLE: &str = "
+$ORIGIN example.com.
+$TTL 300
+@       SOA ns1 hostmaster 1 3600 600 86400 60
+        NS  ns1
+        NS  ns.other.net.
+ns1     A   192.0.2.1
+www     A   192.0.2.10
+        A   192.0.2.11
+; delegated to the sub zone, with glue
+sub     NS  ns.sub
+        NS  ns1
+ns.sub  A   192.0.2.53
+";
+
+    fn example() -> Zone {
+        let origin = name("example.com");
+        let records = zone::parse(EXAMPLE, "example.zone", &origin).unwrap();
+        Zone::new(origin, records).unwrap()
+    }
+
+    fn addresses(records: &[&Record]) -> Vec<RData<'static>> {
+        records
+            .iter()
+            .map(|r| match r.rdata() {
+                RData::A(ip) => RData::A(ip),
+                rdata => panic!("not an address {rdata:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lookup() {
+        let zone = example();
+
+        match zone.lookup(&name("www.example.com"), RRType::A as u16) {
+            Lookup::Answer(rrset) => assert_eq!(
+                addresses(&rrset),
+                vec![
+                    RData::A(Ipv4Addr::new(192, 0, 2, 10)),
+                    RData::A(Ipv4Addr::new(192, 0, 2, 11)),
+                ]
+            ),
+            other => panic!("expected an answer, got {other:?}"),
+        }
+
+        // the apex NS set is an answer, not a referral
+        match zone.lookup(&name("example.com"), RRType::NS as u16) {
+            Lookup::Answer(rrset) => assert_eq!(rrset.len(), 2),
+            other => panic!("expected an answer, got {other:?}"),
+        }
+
+        assert_eq!(
+            zone.lookup(&name("www.example.com"), RRType::MX as u16),
+            Lookup::NoData
+        );
+        assert_eq!(
+            zone.lookup(&name("ftp.example.com"), RRType::A as u16),
+            Lookup::NxDomain
+        );
+        assert!(matches!(zone.soa().rdata(), RData::SOA { minimum: 60, .. }));
+    }
+
+    #[test]
+    fn test_cname() -> Result<(), ZoneError> {
+        let src = "$TTL 60\n@ SOA ns h 1 1 1 1 1\nalias CNAME www\nwww A 192.0.2.1\n";
+        let origin = name("example.com");
+        let zone = Zone::new(origin.clone(), zone::parse(src, "t.zone", &origin)?)?;
+
+        match zone.lookup(&name("alias.example.com"), RRType::A as u16) {
+            Lookup::Cname(r) => {
+                assert_eq!(r.rdata(), RData::CNAME(vec![b"www", b"example", b"com"]))
+            }
+            other => panic!("expected a CNAME, got {other:?}"),
+        }
+        // asking for the CNAME itself does not follow it
+        assert!(matches!(
+            zone.lookup(&name("alias.example.com"), RRType::CNAME as u16),
+            Lookup::Answer(_)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wildcard() -> Result<(), ZoneError> {
+        let src = "
+$TTL 60
+@           SOA ns h 1 1 1 1 1
+*.dev       A   192.0.2.1
+host.dev    A   192.0.2.2
+a.b.dev     A   192.0.2.3
+*.mail      MX  10 mx
+";
+        let origin = name("example");
+        let zone = Zone::new(origin.clone(), zone::parse(src, "t.zone", &origin)?)?;
+        let a = |n| match zone.lookup(&name(n), RRType::A as u16) {
+            Lookup::Answer(rrset) => Some(addresses(&rrset)),
+            Lookup::NxDomain => None,
+            other => panic!("unexpected {other:?} for {n}"),
+        };
+        let wildcard = Some(vec![RData::A(Ipv4Addr::new(192, 0, 2, 1))]);
+
+        assert_eq!(a("x.dev.example"), wildcard);
+        assert_eq!(a("x.y.dev.example"), wildcard);
+        assert_eq!(a("*.dev.example"), wildcard);
+        // names that exist are never covered by the wildcard
+        assert_eq!(
+            a("host.dev.example"),
+            Some(vec![RData::A(Ipv4Addr::new(192, 0, 2, 2))])
+        );
+        assert_eq!(
+            zone.lookup(&name("host.dev.example"), RRType::MX as u16),
+            Lookup::NoData
+        );
+        // b.dev is an empty non-terminal: it exists and is the closest
+        // encloser of z.b.dev, which has no wildcard of its own
+        assert_eq!(
+            zone.lookup(&name("b.dev.example"), RRType::A as u16),
+            Lookup::NoData
+        );
+        assert_eq!(a("z.b.dev.example"), None);
+        assert_eq!(
+            zone.lookup(&name("dev.example"), RRType::A as u16),
+            Lookup::NoData
+        );
+
+        assert_eq!(
+            zone.lookup(&name("x.mail.example"), RRType::A as u16),
+            Lookup::NoData
+        );
+        assert!(matches!(
+            zone.lookup(&name("x.mail.example"), RRType::MX as u16),
+            Lookup::Answer(_)
+        ));
+        assert_eq!(a("x.other.example"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_referral() {
+        let zone = example();
+
+        for n in [
+            "sub.example.com",
+            "host.sub.example.com",
+            "ns.sub.example.com",
+        ] {
+            match zone.lookup(&name(n), RRType::A as u16) {
+                Lookup::Referral { cut, ns, glue } => {
+                    assert_eq!(*cut, name("sub.example.com"));
+                    assert_eq!(ns.len(), 2);
+                    let mut glue = glue
+                        .into_iter()
+                        .map(|(name, r)| (name.clone(), r.rdata()))
+                        .collect::<Vec<_>>();
+                    glue.sort_by(|a, b| a.0.cmp(&b.0));
+                    assert_eq!(
+                        glue,
+                        vec![
+                            (
+                                name("ns1.example.com"),
+                                RData::A(Ipv4Addr::new(192, 0, 2, 1))
+                            ),
+                            (
+                                name("ns.sub.example.com"),
+                                RData::A(Ipv4Addr::new(192, 0, 2, 53))
+                            ),
+                        ]
+                    );
+                }
+                other => panic!("expected a referral for {n}, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_longest_zone() -> Result<(), ZoneError> {
+        let mut zones = Zones::default();
+        zones.add(example());
+        let sub = "@ 60 SOA ns hostmaster 1 3600 600 86400 60\nhost 60 A 192.0.2.99\n";
+        let origin = name("sub.example.com.");
+        zones.add(Zone::new(
+            origin.clone(),
+            zone::parse(sub, "sub.zone", &origin)?,
+        )?);
+
+        let find = |n| zones.find(&name(n)).map(|z| z.origin().to_string());
+        assert_eq!(find("www.example.com"), Some("example.com.".to_string()));
+        assert_eq!(find("Example.COM"), Some("example.com.".to_string()));
+        assert_eq!(
+            find("host.sub.example.com"),
+            Some("sub.example.com.".to_string())
+        );
+        assert_eq!(find("example.org"), None);
+        assert_eq!(find("."), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_zone() {
+        let origin = name("example.com");
+        let records = |src| zone::parse(src, "bad.zone", &origin).unwrap();
+
+        assert!(matches!(
+            Zone::new(origin.clone(), records("$TTL 60\nwww A 192.0.2.1\n")),
+            Err(ZoneError::Invalid { .. })
+        ));
+        assert_eq!(
+            Zone::new(
+                origin.clone(),
+                records("$TTL 60\n@ SOA ns h 1 1 1 1 1\nwww.example.org. A 192.0.2.1\n")
+            )
+            .unwrap_err()
+            .to_string(),
+            "zone example.com.: www.example.org. is outside the zone"
+        );
+    }
+}
@@ -0,0 +1,375 @@
+use crate::name::DomainName;
+use crate::zone::Record;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+
+// Rough bookkeeping cost of a record on top of its RDATA
+const RECORD_OVERHEAD: usize = 64;
+
+// Type 0 is reserved (RFC 6895 3.1), so it never clashes with a real RRset
+// and stands for every type of a name that does not exist
+const NXDOMAIN_TYPE: u16 = 0;
+
+// Owner name, type and class
+type Key = (DomainName, u16, u16);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Cached {
+    Answer(Vec<Record>),
+    // The name has no records of the type, or does not exist at all. Either
+    // way the SOA of the zone that said so is kept with its owner.
+    NoData { zone: DomainName, soa: Record },
+    NxDomain { zone: DomainName, soa: Record },
+}
+
+impl Cached {
+    fn records(&self) -> &[Record] {
+        match self {
+            Cached::Answer(records) => records,
+            Cached::NoData { soa, .. } | Cached::NxDomain { soa, .. } => std::slice::from_ref(soa),
+        }
+    }
+
+    fn with_ttl(&self, ttl: u32) -> Cached {
+        let set = |r: &Record| Record { ttl, ..r.clone() };
+        match self {
+            Cached::Answer(records) => Cached::Answer(records.iter().map(set).collect()),
+            Cached::NoData { zone, soa } => Cached::NoData {
+                zone: zone.clone(),
+                soa: set(soa),
+            },
+            Cached::NxDomain { zone, soa } => Cached::NxDomain {
+                zone: zone.clone(),
+                soa: set(soa),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    data: Cached,
+    stored: Instant,
+    ttl: u32,
+    size: usize,
+    // when the entry was last used, in ticks of the cache
+    used: u64,
+}
+
+/*
+RRsets learned from upstream servers. An RRset lives for the smallest TTL of
+its records (RFC 2181 5.2) and is handed out with the TTL counted down by the
+time it spent here. The total size is kept under max_size by evicting the
+least recently used RRsets first.
+
+Negative answers are kept too (RFC 2308), for the SOA TTL capped to its
+minimum field. NODATA is kept per type, while NXDOMAIN covers every type of
+the name.
+*/
+#[derive(Debug)]
+pub struct Cache {
+    entries: HashMap<Key, Entry>,
+    // keys by the tick they were last used at, oldest first
+    lru: BTreeMap<u64, Key>,
+    tick: u64,
+    size: usize,
+    max_size: usize,
+}
+
+impl Cache {
+    pub fn new(max_size: usize) -> Self {
+        Cache {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+            max_size,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn insert(&mut self, name: DomainName, rtype: u16, class: u16, data: Cached, now: Instant) {
+        let (rtype, ttl) = match &data {
+            Cached::Answer(records) => (rtype, records.iter().map(|r| r.ttl).min().unwrap_or(0)),
+            Cached::NoData { soa, .. } => (rtype, soa.negative_ttl()),
+            Cached::NxDomain { soa, .. } => (NXDOMAIN_TYPE, soa.negative_ttl()),
+        };
+        let key = (name, rtype, class);
+        self.remove(&key);
+        // the name exists after all
+        if rtype != NXDOMAIN_TYPE {
+            self.remove(&(key.0.clone(), NXDOMAIN_TYPE, class));
+        }
+
+        let size = key.0.labels().iter().map(|l| l.len() + 1).sum::<usize>()
+            + data
+                .records()
+                .iter()
+                .map(|r| r.rdata.len() + RECORD_OVERHEAD)
+                .sum::<usize>();
+        // records with a TTL of zero must not be cached (RFC 1035 3.2.1)
+        if ttl == 0 || size > self.max_size {
+            return;
+        }
+
+        while self.size + size > self.max_size {
+            let (_, oldest) = self.lru.pop_first().expect("the cache is not empty");
+            self.remove(&oldest);
+        }
+
+        self.tick += 1;
+        self.size += size;
+        self.lru.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                data,
+                stored: now,
+                ttl,
+                size,
+                used: self.tick,
+            },
+        );
+    }
+
+    // What we know about the name and type with the TTLs counted down, as long
+    // as it has not expired. A name known not to exist has no type at all.
+    pub fn get(
+        &mut self,
+        name: &DomainName,
+        rtype: u16,
+        class: u16,
+        now: Instant,
+    ) -> Option<Cached> {
+        self.get_entry((name.clone(), NXDOMAIN_TYPE, class), now)
+            .or_else(|| self.get_entry((name.clone(), rtype, class), now))
+    }
+
+    fn get_entry(&mut self, key: Key, now: Instant) -> Option<Cached> {
+        let entry = self.entries.get_mut(&key)?;
+
+        let elapsed = now.saturating_duration_since(entry.stored).as_secs();
+        if elapsed >= entry.ttl as u64 {
+            self.remove(&key);
+            return None;
+        }
+
+        self.tick += 1;
+        self.lru.remove(&entry.used);
+        self.lru.insert(self.tick, key);
+        entry.used = self.tick;
+
+        Some(entry.data.with_ttl(entry.ttl - elapsed as u32))
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.used);
+            self.size -= entry.size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_hdr::{RData, RRClass, RRType};
+    use crate::testing::name;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    const A: u16 = RRType::A as u16;
+    const IN: u16 = RRClass::IN as u16;
+
+    fn a(ttl: u32, last: u8) -> Record {
+        Record::new(ttl, &RData::A(Ipv4Addr::new(192, 0, 2, last)))
+    }
+
+    #[test]
+    fn test_ttl_counts_down() {
+        let mut cache = Cache::new(1 << 20);
+        let now = Instant::now();
+        cache.insert(
+            name("example.com"),
+            A,
+            IN,
+            Cached::Answer(vec![a(300, 1), a(60, 2)]),
+            now,
+        );
+
+        // the RRset lives as long as its shortest TTL
+        let rrset = cache.get(&name("Example.COM"), A, IN, now);
+        assert_eq!(rrset, Some(Cached::Answer(vec![a(60, 1), a(60, 2)])));
+
+        let later = now + Duration::from_secs(45);
+        let rrset = cache.get(&name("example.com"), A, IN, later).unwrap();
+        assert!(rrset.records().iter().all(|r| r.ttl == 15));
+
+        let expired = now + Duration::from_secs(60);
+        assert_eq!(cache.get(&name("example.com"), A, IN, expired), None);
+        assert_eq!(cache.len(), 0);
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> Record {
+        Record::new(
+            ttl,
+            &RData::SOA {
+                mname: vec![b"ns", b"example", b"com"],
+                rname: vec![b"hostmaster", b"example", b"com"],
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum,
+            },
+        )
+    }
+
+    #[test]
+    fn test_negative_answers() {
+        let mut cache = Cache::new(1 << 20);
+        let now = Instant::now();
+        let zone = name("example.com");
+
+        // kept for the smaller of the SOA TTL and minimum
+        let nodata = Cached::NoData {
+            zone: zone.clone(),
+            soa: soa(300, 60),
+        };
+        cache.insert(name("www.example.com"), A, IN, nodata, now);
+        let later = now + Duration::from_secs(50);
+        assert_eq!(
+            cache.get(&name("www.example.com"), A, IN, later),
+            Some(Cached::NoData {
+                zone: zone.clone(),
+                soa: soa(10, 60),
+            })
+        );
+        assert!(cache
+            .get(&name("www.example.com"), RRType::MX as u16, IN, now)
+            .is_none());
+        assert!(cache
+            .get(
+                &name("www.example.com"),
+                A,
+                IN,
+                now + Duration::from_secs(60)
+            )
+            .is_none());
+
+        // NXDOMAIN holds for every type of the name
+        let nxdomain = Cached::NxDomain {
+            zone: zone.clone(),
+            soa: soa(30, 60),
+        };
+        cache.insert(name("missing.example.com"), A, IN, nxdomain, now);
+        for rtype in [A, RRType::MX as u16, RRType::TXT as u16] {
+            assert!(matches!(
+                cache.get(&name("missing.example.com"), rtype, IN, now),
+                Some(Cached::NxDomain { soa, .. }) if soa.ttl == 30
+            ));
+        }
+        assert!(cache
+            .get(&name("www.missing.example.com"), A, IN, now)
+            .is_none());
+
+        // until the name shows up
+        cache.insert(
+            name("missing.example.com"),
+            A,
+            IN,
+            Cached::Answer(vec![a(60, 1)]),
+            now,
+        );
+        assert_eq!(
+            cache.get(&name("missing.example.com"), A, IN, now),
+            Some(Cached::Answer(vec![a(60, 1)]))
+        );
+        assert!(cache
+            .get(&name("missing.example.com"), RRType::MX as u16, IN, now)
+            .is_none());
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut cache = Cache::new(1 << 20);
+        let now = Instant::now();
+        cache.insert(
+            name("example.com"),
+            A,
+            IN,
+            Cached::Answer(vec![a(60, 1)]),
+            now,
+        );
+
+        assert!(cache
+            .get(&name("example.com"), A, RRClass::CH as u16, now)
+            .is_none());
+        assert!(cache
+            .get(&name("example.com"), RRType::AAAA as u16, IN, now)
+            .is_none());
+        assert!(cache.get(&name("www.example.com"), A, IN, now).is_none());
+
+        // a new RRset replaces the old one
+        cache.insert(
+            name("example.com"),
+            A,
+            IN,
+            Cached::Answer(vec![a(60, 2)]),
+            now,
+        );
+        assert_eq!(
+            cache.get(&name("example.com"), A, IN, now),
+            Some(Cached::Answer(vec![a(60, 2)]))
+        );
+
+        cache.insert(
+            name("zero.example.com"),
+            A,
+            IN,
+            Cached::Answer(vec![a(0, 1)]),
+            now,
+        );
+        assert!(cache.get(&name("zero.example.com"), A, IN, now).is_none());
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let now = Instant::now();
+        let entry = |n: &str| {
+            name(n).labels().iter().map(|l| l.len() + 1).sum::<usize>() + 4 + RECORD_OVERHEAD
+        };
+        // room for three RRsets of one A record each
+        let mut cache = Cache::new(3 * entry("a.example"));
+
+        for n in ["a.example", "b.example", "c.example"] {
+            cache.insert(name(n), A, IN, Cached::Answer(vec![a(60, 1)]), now);
+        }
+        // using a makes b the least recently used
+        assert!(cache.get(&name("a.example"), A, IN, now).is_some());
+        cache.insert(
+            name("d.example"),
+            A,
+            IN,
+            Cached::Answer(vec![a(60, 1)]),
+            now,
+        );
+
+        assert_eq!(cache.len(), 3);
+        assert!(cache.get(&name("b.example"), A, IN, now).is_none());
+        for n in ["a.example", "c.example", "d.example"] {
+            assert!(cache.get(&name(n), A, IN, now).is_some(), "{n}");
+        }
+
+        // an RRset bigger than the whole cache is not stored
+        let big = (0..100).map(|i| a(60, i)).collect();
+        cache.insert(name("big.example"), A, IN, Cached::Answer(big), now);
+        assert!(cache.get(&name("big.example"), A, IN, now).is_none());
+        assert_eq!(cache.len(), 3);
+    }
+}
@@ -19,13 +19,21 @@ use anyhow::Result;
 +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
  */
 
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::name::DomainName;
+
 use bytes::{BufMut, Bytes, BytesMut};
 use nom::{
-    combinator::{map, verify},
-    multi::{length_data, many_m_n},
+    bytes::complete::take as take_bytes,
+    combinator::{all_consuming, map, rest},
+    multi::{length_data, many0},
     number::complete::{be_u16, be_u32, be_u8},
     sequence::tuple,
+    Offset,
 };
+use thiserror::Error;
 
 use nom::bits::complete::take;
 
@@ -51,7 +59,7 @@ impl Flags {
         (flags_h as u16) << 8 | (flags_l as u16)
     }
 
-    fn parse_flags<'a>(input: (&'a [u8], usize)) -> nom::IResult<(&'a [u8], usize), Flags> {
+    fn parse_flags(input: (&[u8], usize)) -> nom::IResult<(&[u8], usize), Flags> {
         map(
             tuple((
                 take(1u8),
@@ -77,7 +85,7 @@ impl Flags {
 }
 
 #[repr(u8)]
-#[allow(dead_code)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 pub enum OpCode {
     QUERY = 0,
     IQUERY = 1,
@@ -93,53 +101,116 @@ pub enum RCode {
     NameError = 3,
     NotImplemted = 4,
     Refused = 5,
+    BadVers = 16, // only representable with an OPT record
 }
 
 #[derive(Debug)]
 pub struct DNSHdr<'a> {
     pub id: u16,
     pub flags: Flags,
-    pub nscount: u16,
-    pub arcount: u16,
     pub queries: Vec<Query<'a>>,
     pub answers: Vec<Answer<'a>>,
+    pub authorities: Vec<Answer<'a>>,
+    pub additionals: Vec<Answer<'a>>,
+    pub edns: Option<Edns<'a>>,
 }
 
 impl<'a> DNSHdr<'a> {
     pub fn new(id: u16, flags: Flags, queries: Vec<Query<'a>>, answers: Vec<Answer<'a>>) -> Self {
         DNSHdr {
-            id: id,
-            flags: flags,
-            nscount: 0,
-            arcount: 0,
-            queries: queries,
-            answers: answers,
+            id,
+            flags,
+            queries,
+            answers,
+            authorities: vec![],
+            additionals: vec![],
+            edns: None,
+        }
+    }
+
+    // The full 12 bit RCODE, with the upper bits taken from the OPT record
+    pub fn rcode(&self) -> u16 {
+        let extended = self.edns.as_ref().map_or(0, |e| e.extended_rcode);
+        (extended as u16) << 4 | self.flags.rcode as u16
+    }
+
+    // Extended RCODEs need an OPT record to carry their upper bits
+    pub fn set_rcode(&mut self, rcode: u16) {
+        self.flags.rcode = (rcode & 0b1111) as u8;
+        if let Some(edns) = self.edns.as_mut() {
+            edns.extended_rcode = (rcode >> 4) as u8;
         }
     }
 
     pub fn to_bytes(&self) -> Bytes {
         let mut buf: BytesMut = BytesMut::with_capacity(DNS_HDR_SIZE);
+        let mut names = NameCompression::default();
 
         buf.put_u16(self.id);
         buf.put_u16(self.flags.compress_u16());
         buf.put_u16(self.queries.len() as u16);
         buf.put_u16(self.answers.len() as u16);
-        buf.put_u16(self.nscount);
-        buf.put_u16(self.arcount);
+        buf.put_u16(self.authorities.len() as u16);
+        buf.put_u16((self.additionals.len() + self.edns.is_some() as usize) as u16);
 
         for q in self.queries.iter() {
-            q.to_bytes(&mut buf);
+            q.to_bytes(&mut buf, &mut names);
         }
 
         for a in self.answers.iter() {
-            a.to_bytes(&mut buf);
+            a.to_bytes(&mut buf, &mut names);
+        }
+
+        for a in self.authorities.iter() {
+            a.to_bytes(&mut buf, &mut names);
+        }
+
+        for a in self.additionals.iter() {
+            a.to_bytes(&mut buf, &mut names);
+        }
+
+        if let Some(edns) = &self.edns {
+            edns.to_bytes(&mut buf);
         }
 
         buf.freeze()
     }
 
+    /*
+    Encodes the message in at most limit bytes. Whole RRsets are dropped from
+    the end of the message until it fits: additional data first, which the
+    client can do without, then authority and answer RRsets, which sets TC so
+    the client knows to retry over TCP (RFC 2181 9).
+    */
+    pub fn truncate_to(&mut self, limit: usize) -> Bytes {
+        loop {
+            let bytes = self.to_bytes();
+            if bytes.len() <= limit {
+                return bytes;
+            }
+
+            if !self.additionals.is_empty() {
+                pop_rrset(&mut self.additionals);
+                continue;
+            }
+
+            self.flags.tc = 1;
+            if !self.authorities.is_empty() {
+                pop_rrset(&mut self.authorities);
+            } else if !self.answers.is_empty() {
+                pop_rrset(&mut self.answers);
+            } else if self.edns.is_some() || !self.queries.is_empty() {
+                // not even the question fits, fall back to a bare header
+                self.edns = None;
+                self.queries.clear();
+            } else {
+                return bytes.slice(..DNS_HDR_SIZE.min(limit));
+            }
+        }
+    }
 
-    pub fn from_bytes(buf: &'a [u8]) -> nom::IResult<&'a [u8], Self> {
+    // The header with its section counts; the sections are left empty
+    fn parse_header(buf: &'a [u8]) -> Result<(&'a [u8], Self, [u16; 4]), WireError> {
         let (rest, (id, flags, qdcount, ancount, nscount, arcount)) = tuple((
             be_u16,
             nom::bits::bits(Flags::parse_flags),
@@ -147,23 +218,62 @@ impl<'a> DNSHdr<'a> {
             be_u16,
             be_u16,
             be_u16,
-        ))(buf)?;
-
-        let (rest, queries) = Query::from_bytes(rest, qdcount as usize, buf)?;
-        let (rest, answers) = Answer::from_bytes(rest, ancount as usize, buf)?;
+        ))(buf)
+        .map_err(|_: nom::Err<nom::error::Error<_>>| WireError::TruncatedHeader(buf.len()))?;
 
         Ok((
             rest,
-            DNSHdr {
-                id,
-                flags,
-                nscount,
-                arcount,
-                queries,
-                answers,
-            },
+            DNSHdr::new(id, flags, vec![], vec![]),
+            [qdcount, ancount, nscount, arcount],
         ))
     }
+
+    // Decodes only the header and the question section, for messages whose
+    // records may be cut short (truncated responses)
+    pub fn question_from_bytes(buf: &'a [u8]) -> Result<Self, WireError> {
+        let (rest, mut hdr, [qdcount, ..]) = Self::parse_header(buf)?;
+        (_, hdr.queries) = Query::from_bytes(rest, qdcount as usize, buf).map_err(wire_error)?;
+
+        Ok(hdr)
+    }
+
+    pub fn from_bytes(buf: &'a [u8]) -> Result<Self, WireError> {
+        let (rest, DNSHdr { id, flags, .. }, [qdcount, ancount, nscount, arcount]) =
+            Self::parse_header(buf)?;
+
+        let (rest, queries) = Query::from_bytes(rest, qdcount as usize, buf).map_err(wire_error)?;
+        let (rest, answers) =
+            Answer::from_bytes(rest, ancount as usize, buf).map_err(wire_error)?;
+        let (rest, authorities) =
+            Answer::from_bytes(rest, nscount as usize, buf).map_err(wire_error)?;
+        let (rest, mut additionals) =
+            Answer::from_bytes(rest, arcount as usize, buf).map_err(wire_error)?;
+
+        if !rest.is_empty() {
+            return Err(WireError::TrailingBytes(rest.len()));
+        }
+
+        let mut opts = additionals
+            .iter()
+            .enumerate()
+            .filter(|(_, a)| a.qtype == RRType::OPT as u16)
+            .map(|(i, _)| i);
+        let edns = match (opts.next(), opts.next()) {
+            (None, _) => None,
+            (Some(i), None) => Some(Edns::from_answer(additionals.remove(i))?),
+            (Some(_), Some(_)) => return Err(WireError::BadOpt),
+        };
+
+        Ok(DNSHdr {
+            id,
+            flags,
+            queries,
+            answers,
+            authorities,
+            additionals,
+            edns,
+        })
+    }
 }
 
 /*
@@ -186,9 +296,14 @@ pub struct Query<'a> {
     pub qclass: u16,
 }
 
+// QTYPE asking for records of every type (RFC 1035 3.2.3)
+pub const QTYPE_ANY: u16 = 255;
+// QCLASS asking for records of every class (RFC 1035 3.2.5)
+pub const QCLASS_ANY: u16 = 255;
+
 #[repr(u16)]
-#[derive(Debug)]
-#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 pub enum RRType {
     A = 1,      // Host Address
     NS = 2,     //an authoritative name server
@@ -206,6 +321,10 @@ pub enum RRType {
     MINFO = 14, // mailbox or mail list information
     MX = 15,    // mail exchange
     TXT = 16,   // text strings
+    AAAA = 28,  // IPv6 host address
+    SRV = 33,   // service locator
+    OPT = 41,   // EDNS(0) pseudo-record
+    CAA = 257,  // certification authority authorization
 }
 #[repr(u16)]
 #[derive(Debug)]
@@ -217,71 +336,255 @@ pub enum RRClass {
     HS = 4, // Hesiod [Dyer 87]
 }
 
-fn parse_labels<'a>(buf: &'a [u8], pkt: &'a [u8]) -> nom::IResult<&'a [u8], Vec<&'a [u8]>> {
+// Names compare case-insensitively (RFC 4343)
+fn same_name(a: &[&[u8]], b: &[&[u8]]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+fn put_labels(buf: &mut BytesMut, labels: &[&[u8]]) {
+    labels.iter().for_each(|&l| {
+        buf.put_u8(l.len() as u8);
+        buf.extend_from_slice(l);
+    });
+    buf.put_u8(0);
+}
+
+/*
+Names already written into the message being encoded, keyed by their
+lowercased label sequence and mapped to the offset they start at. Every
+suffix of a written name is recorded, so later names only write the labels
+that are not already in the message followed by a pointer (RFC 1035 4.1.4).
+The buffer must start at the beginning of the message for offsets to be valid.
+*/
+#[derive(Debug, Default)]
+pub struct NameCompression {
+    offsets: HashMap<Vec<Vec<u8>>, u16>,
+}
+
+impl NameCompression {
+    const MAX_OFFSET: usize = 0b0011_1111_1111_1111;
+
+    pub fn put_name(&mut self, buf: &mut BytesMut, labels: &[&[u8]]) {
+        for (i, &label) in labels.iter().enumerate() {
+            let suffix = labels[i..]
+                .iter()
+                .map(|l| l.to_ascii_lowercase())
+                .collect::<Vec<_>>();
+
+            if let Some(offset) = self.offsets.get(&suffix) {
+                buf.put_u16(0b1100_0000_0000_0000 | offset);
+                return;
+            }
+
+            if buf.len() <= Self::MAX_OFFSET {
+                self.offsets.insert(suffix, buf.len() as u16);
+            }
+            buf.put_u8(label.len() as u8);
+            buf.extend_from_slice(label);
+        }
+        buf.put_u8(0);
+    }
+}
+
+pub const MAX_LABEL_LEN: usize = 63;
+pub const MAX_NAME_LEN: usize = 255;
+// UDP payload size we advertise and accept with EDNS
+pub const EDNS_UDP_SIZE: u16 = 4096;
+// Longest CNAME chain we follow, through our own zones or while resolving
+pub const MAX_CNAME_CHAIN: usize = 8;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum NameError {
+    #[error("name runs past the end of the packet")]
+    Truncated,
+    #[error("label length {0} exceeds {MAX_LABEL_LEN} bytes")]
+    LabelTooLong(u8),
+    #[error("reserved label type {0:#04x}")]
+    BadLabelType(u8),
+    #[error("name exceeds {MAX_NAME_LEN} bytes")]
+    NameTooLong,
+    #[error("compression pointer to offset {0} does not point backwards")]
+    ForwardPointer(usize),
+    #[error("compression pointer to offset {0} loops back into the name")]
+    PointerLoop(usize),
+}
+
+/*
+Decodes a (possibly compressed) name starting at buf, which must be a slice of
+pkt. Every pointer has to jump strictly before the start of the labels that are
+currently being read: pointing at or after itself is a forward pointer, pointing
+into the labels already read in this run is a loop. That guarantees every jump
+makes progress towards the start of the packet, so decoding always terminates.
+*/
+pub fn decode_name<'a>(
+    buf: &'a [u8],
+    pkt: &'a [u8],
+) -> Result<(&'a [u8], Vec<&'a [u8]>), NameError> {
+    let start = pkt.offset(buf);
     let mut labels = vec![];
-    let mut rest = buf;
+    // the wire length of the name, including the terminating root label
+    let mut name_len = 1;
+    let mut run_start = start;
+    let mut pos = start;
+    // until the first pointer only the bytes of buf belong to the name
+    let mut data = pkt.get(..start + buf.len()).ok_or(NameError::Truncated)?;
+    let mut end = None;
 
     loop {
-        if let Ok((r, label)) =
-            length_data(verify(be_u8::<_, nom::error::Error<_>>, |&l| l < 127))(rest)
-        {
-            rest = r;
-            if label.len() > 0 {
+        let len = *data.get(pos).ok_or(NameError::Truncated)?;
+        match len & 0b1100_0000 {
+            0b0000_0000 if len == 0 => {
+                let end = end.unwrap_or(pos + 1);
+                return Ok((&buf[end - start..], labels));
+            }
+            0b0000_0000 => {
+                let label = data
+                    .get(pos + 1..pos + 1 + len as usize)
+                    .ok_or(NameError::Truncated)?;
+                name_len += 1 + label.len();
+                if name_len > MAX_NAME_LEN {
+                    return Err(NameError::NameTooLong);
+                }
                 labels.push(label);
-            } else {
-                break;
+                pos += 1 + label.len();
             }
-        } else {
-            let (r, offset) = be_u16(rest)?;
-            let offset = (offset & 0b0011_1111_1111_1111) as usize;
-            if offset < pkt.len() {
-                let (_, compress_labels) = parse_labels(&pkt[offset..], pkt)?;
-                labels.extend(compress_labels);
-
-                rest = r;
-                break;
+            0b1100_0000 => {
+                let low = *data.get(pos + 1).ok_or(NameError::Truncated)?;
+                let target = ((len as usize & 0b0011_1111) << 8) | low as usize;
+                if target >= pos {
+                    return Err(NameError::ForwardPointer(target));
+                }
+                if target >= run_start {
+                    return Err(NameError::PointerLoop(target));
+                }
+                end.get_or_insert(pos + 2);
+                data = pkt;
+                run_start = target;
+                pos = target;
             }
+            0b0100_0000 => return Err(NameError::LabelTooLong(len)),
+            _ => return Err(NameError::BadLabelType(len)),
+        }
+    }
+}
+
+fn parse_labels<'a>(
+    buf: &'a [u8],
+    pkt: &'a [u8],
+) -> nom::IResult<&'a [u8], Vec<&'a [u8]>, WireError> {
+    decode_name(buf, pkt).map_err(|e| nom::Err::Failure(e.into()))
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum WireError {
+    #[error("packet of {0} bytes is shorter than the {DNS_HDR_SIZE} byte header")]
+    TruncatedHeader(usize),
+    #[error("packet ends in the middle of a record")]
+    Truncated,
+    #[error("bad label: {0}")]
+    BadLabel(NameError),
+    #[error("pointer loop: {0}")]
+    PointerLoop(NameError),
+    #[error("section count is {expected} but only {found} records are present")]
+    CountMismatch { expected: usize, found: usize },
+    #[error("{0} trailing bytes after the last record")]
+    TrailingBytes(usize),
+    #[error("RDATA of type {rtype} does not match its RDLENGTH of {rdlength}")]
+    RdataOverrun { rtype: u16, rdlength: u16 },
+    #[error("malformed or duplicated OPT record")]
+    BadOpt,
+}
+
+impl From<NameError> for WireError {
+    fn from(e: NameError) -> Self {
+        match e {
+            NameError::Truncated => WireError::Truncated,
+            NameError::ForwardPointer(_) | NameError::PointerLoop(_) => WireError::PointerLoop(e),
+            _ => WireError::BadLabel(e),
         }
     }
+}
+
+// Plain nom parsers (be_u16, take, ...) only fail when they run out of input
+impl<I> nom::error::ParseError<I> for WireError {
+    fn from_error_kind(_: I, _: nom::error::ErrorKind) -> Self {
+        WireError::Truncated
+    }
 
-    Ok((rest, labels))
+    fn append(_: I, _: nom::error::ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+fn wire_error(e: nom::Err<WireError>) -> WireError {
+    match e {
+        nom::Err::Error(e) | nom::Err::Failure(e) => e,
+        nom::Err::Incomplete(_) => WireError::Truncated,
+    }
 }
 
+// Parses exactly n records, telling a count that runs past the end of the
+// packet apart from a record that is cut short.
+fn parse_records<'a, T>(
+    buf: &'a [u8],
+    n: usize,
+    mut parse: impl FnMut(&'a [u8]) -> nom::IResult<&'a [u8], T, WireError>,
+) -> nom::IResult<&'a [u8], Vec<T>, WireError> {
+    let mut records = vec![];
+    let mut rest = buf;
+
+    for found in 0..n {
+        if rest.is_empty() {
+            return Err(nom::Err::Failure(WireError::CountMismatch {
+                expected: n,
+                found,
+            }));
+        }
+        let (r, record) = parse(rest).map_err(|e| nom::Err::Failure(wire_error(e)))?;
+        records.push(record);
+        rest = r;
+    }
+
+    Ok((rest, records))
+}
 
 impl<'a> Query<'a> {
-    pub fn from_bytes(buf: &'a [u8], n: usize, pkt: &'a [u8]) -> nom::IResult<&'a [u8], Vec<Self>> {
-        let (rest, queries) = many_m_n(
-            n,
+    pub fn from_bytes(
+        buf: &'a [u8],
+        n: usize,
+        pkt: &'a [u8],
+    ) -> nom::IResult<&'a [u8], Vec<Self>, WireError> {
+        parse_records(
+            buf,
             n,
             map(
                 tuple((|i| parse_labels(i, pkt), be_u16, be_u16)),
                 |(labels, qtype, qclass)| Query {
                     name: labels,
-                    qtype: qtype,
-                    qclass: qclass,
+                    qtype,
+                    qclass,
                 },
             ),
-        )(buf)?;
-
-        Ok((rest, queries))
+        )
     }
 
-    pub fn to_bytes(&self, buf: &mut BytesMut) {
-        self.name.iter().for_each(|&l| {
-            buf.put_u8(l.len() as u8);
-            buf.extend_from_slice(l);
-        });
-        buf.put_u8(0);
+    pub fn to_bytes(&self, buf: &mut BytesMut, names: &mut NameCompression) {
+        names.put_name(buf, &self.name);
         buf.put_u16(self.qtype);
         buf.put_u16(self.qclass);
     }
 
-    pub fn domain(&self) -> String {
-        self.name
-            .iter()
-            .filter_map(|l| std::str::from_utf8(l).ok())
-            .collect::<Vec<_>>()
-            .join(".")
+    pub fn same_question(&self, other: &Query) -> bool {
+        self.qtype == other.qtype
+            && self.qclass == other.qclass
+            && same_name(&self.name, &other.name)
+    }
+
+    pub fn domain(&self) -> DomainName {
+        DomainName::from_labels(&self.name)
     }
 }
 
@@ -312,64 +615,319 @@ pub struct Answer<'a> {
     pub qtype: u16,
     pub qclass: u16,
     pub ttl: u32,
-    pub rddata: &'a [u8],
+    pub rdata: RData<'a>,
 }
 
 impl<'a> Answer<'a> {
-    pub fn new(
-        name: Vec<&'a [u8]>,
-        qtype: RRType,
-        qclass: RRClass,
-        ttl: u32,
-        data: &'a [u8],
-    ) -> Self {
-        
-
+    pub fn new(name: Vec<&'a [u8]>, qclass: RRClass, ttl: u32, rdata: RData<'a>) -> Self {
         Answer {
             name,
-            qtype: qtype as u16,
+            qtype: rdata.rtype(),
             qclass: qclass as u16,
             ttl,
-            rddata: data,
+            rdata,
         }
     }
 
-    pub fn from_bytes(buf: &'a [u8], n: usize, pkt:&'a [u8]) -> nom::IResult<&'a [u8], Vec<Self>> {
-        let (rest, responses) = many_m_n(
-            n,
-            n,
-            map(
-                tuple((
-                    |i| {parse_labels(i, pkt)},
-                    be_u16,
-                    be_u16,
-                    be_u32,
-                    length_data(be_u16),
-                )),
-                |(labels, qtype, qclass, ttl, rddata)| Answer {
+    pub fn from_bytes(
+        buf: &'a [u8],
+        n: usize,
+        pkt: &'a [u8],
+    ) -> nom::IResult<&'a [u8], Vec<Self>, WireError> {
+        parse_records(buf, n, |i| {
+            let (i, (labels, qtype, qclass, ttl, rdlength)) =
+                tuple((|i| parse_labels(i, pkt), be_u16, be_u16, be_u32, be_u16))(i)?;
+            let (i, rdata) = take_bytes(rdlength)(i).map_err(|_: nom::Err<WireError>| {
+                nom::Err::Failure(WireError::RdataOverrun {
+                    rtype: qtype,
+                    rdlength,
+                })
+            })?;
+            let rdata = RData::from_bytes(qtype, rdata, pkt).map_err(nom::Err::Failure)?;
+
+            Ok((
+                i,
+                Answer {
                     name: labels,
-                    qtype: qtype,
-                    qclass: qclass,
-                    ttl: ttl,
-                    rddata,
+                    qtype,
+                    qclass,
+                    ttl,
+                    rdata,
                 },
-            ),
-        )(buf)?;
+            ))
+        })
+    }
 
-        Ok((rest, responses))
+    // Records with the same owner name, type and class form an RRset
+    pub fn same_rrset(&self, other: &Answer) -> bool {
+        self.qtype == other.qtype
+            && self.qclass == other.qclass
+            && same_name(&self.name, &other.name)
     }
 
-    pub fn to_bytes(&self, buf: &mut BytesMut) {
-        self.name.iter().for_each(|&l| {
-            buf.put_u8(l.len() as u8);
-            buf.extend_from_slice(l);
-        });
-        buf.put_u8(0);
+    pub fn to_bytes(&self, buf: &mut BytesMut, names: &mut NameCompression) {
+        names.put_name(buf, &self.name);
         buf.put_u16(self.qtype);
         buf.put_u16(self.qclass);
         buf.put_u32(self.ttl);
-        buf.put_u16(self.rddata.len() as u16);
-        buf.extend(self.rddata);
+
+        // RDLENGTH is only known once the RDATA has been written
+        let len_pos = buf.len();
+        buf.put_u16(0);
+        self.rdata.to_bytes(buf, names);
+        let len = (buf.len() - len_pos - 2) as u16;
+        buf[len_pos..len_pos + 2].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+// Removes every record of the RRset the last record belongs to
+fn pop_rrset(records: &mut Vec<Answer>) {
+    if let Some(last) = records.pop() {
+        records.retain(|a| !a.same_rrset(&last));
+    }
+}
+
+/*
+Typed RDATA for the record types we know about. Names inside the RDATA are
+decompressed against the whole packet, anything else is kept as raw bytes.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum RData<'a> {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    NS(Vec<&'a [u8]>),
+    CNAME(Vec<&'a [u8]>),
+    SOA {
+        mname: Vec<&'a [u8]>,
+        rname: Vec<&'a [u8]>,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    PTR(Vec<&'a [u8]>),
+    MX {
+        preference: u16,
+        exchange: Vec<&'a [u8]>,
+    },
+    TXT(Vec<&'a [u8]>),
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: Vec<&'a [u8]>,
+    },
+    CAA {
+        flags: u8,
+        tag: &'a [u8],
+        value: &'a [u8],
+    },
+    Unknown(u16, &'a [u8]),
+}
+
+impl<'a> RData<'a> {
+    pub fn rtype(&self) -> u16 {
+        match self {
+            RData::A(_) => RRType::A as u16,
+            RData::AAAA(_) => RRType::AAAA as u16,
+            RData::NS(_) => RRType::NS as u16,
+            RData::CNAME(_) => RRType::CNAME as u16,
+            RData::SOA { .. } => RRType::SOA as u16,
+            RData::PTR(_) => RRType::PTR as u16,
+            RData::MX { .. } => RRType::MX as u16,
+            RData::TXT(_) => RRType::TXT as u16,
+            RData::SRV { .. } => RRType::SRV as u16,
+            RData::CAA { .. } => RRType::CAA as u16,
+            RData::Unknown(rtype, _) => *rtype,
+        }
+    }
+
+    pub fn from_bytes(rtype: u16, data: &'a [u8], pkt: &'a [u8]) -> Result<Self, WireError> {
+        let name = move |i| parse_labels(i, pkt);
+        let overrun = WireError::RdataOverrun {
+            rtype,
+            rdlength: data.len() as u16,
+        };
+
+        let i = data;
+        let parsed: nom::IResult<&'a [u8], Self, WireError> = match rtype {
+            t if t == RRType::A as u16 => map(take_bytes(4usize), |b: &[u8]| {
+                RData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+            })(i),
+            t if t == RRType::AAAA as u16 => map(take_bytes(16usize), |b: &[u8]| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(b);
+                RData::AAAA(Ipv6Addr::from(octets))
+            })(i),
+            t if t == RRType::NS as u16 => map(name, RData::NS)(i),
+            t if t == RRType::CNAME as u16 => map(name, RData::CNAME)(i),
+            t if t == RRType::PTR as u16 => map(name, RData::PTR)(i),
+            t if t == RRType::SOA as u16 => map(
+                tuple((name, name, be_u32, be_u32, be_u32, be_u32, be_u32)),
+                |(mname, rname, serial, refresh, retry, expire, minimum)| RData::SOA {
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                },
+            )(i),
+            t if t == RRType::MX as u16 => {
+                map(tuple((be_u16, name)), |(preference, exchange)| RData::MX {
+                    preference,
+                    exchange,
+                })(i)
+            }
+            t if t == RRType::TXT as u16 => map(many0(length_data(be_u8)), RData::TXT)(i),
+            t if t == RRType::SRV as u16 => map(
+                tuple((be_u16, be_u16, be_u16, name)),
+                |(priority, weight, port, target)| RData::SRV {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                },
+            )(i),
+            t if t == RRType::CAA as u16 => map(
+                tuple((be_u8, length_data(be_u8), rest)),
+                |(flags, tag, value)| RData::CAA { flags, tag, value },
+            )(i),
+            _ => map(rest, |b| RData::Unknown(rtype, b))(i),
+        };
+
+        match parsed.map_err(wire_error) {
+            Ok(([], rdata)) => Ok(rdata),
+            Ok(_) | Err(WireError::Truncated) => Err(overrun),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Only the RFC 1035 types may carry compressed names (RFC 3597 4), so
+    // SRV targets are always written out in full.
+    pub fn to_bytes(&self, buf: &mut BytesMut, names: &mut NameCompression) {
+        match self {
+            RData::A(ip) => buf.extend_from_slice(&ip.octets()),
+            RData::AAAA(ip) => buf.extend_from_slice(&ip.octets()),
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => names.put_name(buf, name),
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                names.put_name(buf, mname);
+                names.put_name(buf, rname);
+                buf.put_u32(*serial);
+                buf.put_u32(*refresh);
+                buf.put_u32(*retry);
+                buf.put_u32(*expire);
+                buf.put_u32(*minimum);
+            }
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                buf.put_u16(*preference);
+                names.put_name(buf, exchange);
+            }
+            RData::TXT(strings) => strings.iter().for_each(|s| {
+                buf.put_u8(s.len() as u8);
+                buf.extend_from_slice(s);
+            }),
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                buf.put_u16(*priority);
+                buf.put_u16(*weight);
+                buf.put_u16(*port);
+                put_labels(buf, target);
+            }
+            RData::CAA { flags, tag, value } => {
+                buf.put_u8(*flags);
+                buf.put_u8(tag.len() as u8);
+                buf.extend_from_slice(tag);
+                buf.extend_from_slice(value);
+            }
+            RData::Unknown(_, data) => buf.extend_from_slice(data),
+        }
+    }
+}
+
+/*
+EDNS(0) OPT pseudo-record (RFC 6891). It lives in the additional section but
+is kept apart from the other records since its fields are overloaded:
+
+NAME      root
+TYPE      OPT
+CLASS     requestor's UDP payload size
+TTL       EXTENDED-RCODE (8) | VERSION (8) | DO (1) | Z (15)
+RDATA     {OPTION-CODE (16), OPTION-LENGTH (16), OPTION-DATA}*
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns<'a> {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<(u16, &'a [u8])>,
+}
+
+impl<'a> Edns<'a> {
+    pub fn new(udp_payload_size: u16) -> Self {
+        Edns {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: vec![],
+        }
+    }
+
+    fn from_answer(opt: Answer<'a>) -> Result<Self, WireError> {
+        let data = match opt.rdata {
+            RData::Unknown(_, data) if opt.name.is_empty() => data,
+            _ => return Err(WireError::BadOpt),
+        };
+        let (_, options) = all_consuming(many0(tuple((be_u16, length_data(be_u16)))))(data)
+            .map_err(|_: nom::Err<WireError>| WireError::BadOpt)?;
+
+        Ok(Edns {
+            udp_payload_size: opt.qclass,
+            extended_rcode: (opt.ttl >> 24) as u8,
+            version: (opt.ttl >> 16) as u8,
+            dnssec_ok: opt.ttl & 0x8000 != 0,
+            options,
+        })
+    }
+
+    pub fn to_bytes(&self, buf: &mut BytesMut) {
+        buf.put_u8(0);
+        buf.put_u16(RRType::OPT as u16);
+        buf.put_u16(self.udp_payload_size);
+        buf.put_u8(self.extended_rcode);
+        buf.put_u8(self.version);
+        buf.put_u16(if self.dnssec_ok { 0x8000 } else { 0 });
+        buf.put_u16(
+            self.options
+                .iter()
+                .map(|(_, data)| 4 + data.len())
+                .sum::<usize>() as u16,
+        );
+        for (code, data) in self.options.iter() {
+            buf.put_u16(*code);
+            buf.put_u16(data.len() as u16);
+            buf.extend_from_slice(data);
+        }
     }
 }
 
@@ -408,9 +966,9 @@ mod tests {
 
         println!("{buf:?}");
 
-        let (_, qs) = Query::from_bytes(&buf[DNS_HDR_SIZE..], 1, &buf).unwrap();
+        let (_, qs) = Query::from_bytes(&buf[DNS_HDR_SIZE..], 1, buf).unwrap();
 
-        let q = qs.iter().next().unwrap();
+        let q = qs.first().unwrap();
 
         //assert_eq!(q.domain(), "google.com");
 
@@ -434,7 +992,7 @@ mod tests {
 
         let (_, qs) = Query::from_bytes(&buf[DNS_HDR_SIZE..], 2, buf).unwrap();
 
-        let q = qs.iter().skip(1).next().unwrap();
+        let q = qs.get(1).unwrap();
         println!("{q:?}");
 
         //assert_eq!(q.domain(), "google.com");
@@ -454,20 +1012,616 @@ mod tests {
         let domain = "google.com";
 
         let (ttl, data) = rr_db[domain];
-        let data = data.octets();
 
         let answer = Answer::new(
             vec![&[0x03, 10, 20, 30, 0x0]],
-            RRType::A,
             RRClass::IN,
             ttl,
-            &data,
+            RData::A(data),
         );
         let mut buf = BytesMut::new();
-        answer.to_bytes(&mut buf);
+        answer.to_bytes(&mut buf, &mut NameCompression::default());
 
         println!("{answer:?} -> {buf:?}");
 
         Ok(())
     }
+
+    #[test]
+    fn test_authority_additional_roundtrip() -> Result<()> {
+        let flags = Flags {
+            qr: 1,
+            opcode: 0,
+            aa: 0,
+            tc: 0,
+            rd: 0,
+            ra: 0,
+            rcode: 0,
+        };
+        let glue = Ipv4Addr::new(10, 0, 0, 1);
+        let mut hdr = DNSHdr::new(
+            4321,
+            flags,
+            vec![Query {
+                name: vec![b"example", b"com"],
+                qtype: RRType::A as u16,
+                qclass: RRClass::IN as u16,
+            }],
+            vec![],
+        );
+        hdr.authorities.push(Answer::new(
+            vec![b"example", b"com"],
+            RRClass::IN,
+            300,
+            RData::NS(vec![b"ns"]),
+        ));
+        hdr.additionals
+            .push(Answer::new(vec![b"ns"], RRClass::IN, 300, RData::A(glue)));
+
+        let bytes = hdr.to_bytes();
+        assert_eq!(&bytes[8..12], &[0, 1, 0, 1]);
+
+        let decoded = DNSHdr::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.queries.len(), 1);
+        assert_eq!(decoded.authorities.len(), 1);
+        assert_eq!(decoded.additionals.len(), 1);
+        assert_eq!(decoded.authorities[0].rdata, RData::NS(vec![b"ns"]));
+        assert_eq!(decoded.additionals[0].name, vec![b"ns".as_slice()]);
+        assert_eq!(decoded.additionals[0].rdata, RData::A(glue));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rdata_roundtrip() -> Result<()> {
+        let records = vec![
+            RData::A(Ipv4Addr::new(192, 168, 1, 1)),
+            RData::AAAA("2001:db8::1".parse().unwrap()),
+            RData::NS(vec![b"ns1", b"example", b"com"]),
+            RData::CNAME(vec![b"www", b"example", b"com"]),
+            RData::SOA {
+                mname: vec![b"ns1", b"example", b"com"],
+                rname: vec![b"hostmaster", b"example", b"com"],
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            },
+            RData::PTR(vec![b"host", b"example", b"com"]),
+            RData::MX {
+                preference: 10,
+                exchange: vec![b"mail", b"example", b"com"],
+            },
+            RData::TXT(vec![b"v=spf1 -all", b""]),
+            RData::SRV {
+                priority: 1,
+                weight: 5,
+                port: 5060,
+                target: vec![b"sip", b"example", b"com"],
+            },
+            RData::CAA {
+                flags: 0,
+                tag: b"issue",
+                value: b"letsencrypt.org",
+            },
+            RData::Unknown(99, &[1, 2, 3]),
+        ];
+
+        for rdata in records {
+            let answer = Answer::new(vec![b"example", b"com"], RRClass::IN, 60, rdata.clone());
+            let mut buf = BytesMut::new();
+            answer.to_bytes(&mut buf, &mut NameCompression::default());
+
+            let (rest, decoded) = Answer::from_bytes(&buf, 1, &buf).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(decoded[0].qtype, rdata.rtype());
+            assert_eq!(decoded[0].rdata, rdata);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rdata_compressed_name() -> Result<()> {
+        // question for abc.com followed by a CNAME answer pointing at offset 12
+        let buf: &[u8] = &[
+            0, 1, 129, 0, 0, 1, 0, 1, 0, 0, 0, 0, 3, 97, 98, 99, 3, 99, 111, 109, 0, 0, 5, 0, 1,
+            192, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6, 3, 119, 119, 119, 192, 12,
+        ];
+
+        let hdr = DNSHdr::from_bytes(buf).unwrap();
+        assert_eq!(
+            hdr.answers[0].rdata,
+            RData::CNAME(vec![b"www", b"abc", b"com"])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rdata_bad_length() {
+        // an A record with only three bytes of RDATA
+        let buf: &[u8] = &[1, 97, 0, 0, 1, 0, 1, 0, 0, 0, 60, 0, 3, 10, 0, 0];
+        assert!(Answer::from_bytes(buf, 1, buf).is_err());
+    }
+
+    #[test]
+    fn test_name_compression() -> Result<()> {
+        let flags = Flags {
+            qr: 1,
+            opcode: 0,
+            aa: 0,
+            tc: 0,
+            rd: 0,
+            ra: 0,
+            rcode: 0,
+        };
+        let mut hdr = DNSHdr::new(
+            1,
+            flags,
+            vec![Query {
+                name: vec![b"www", b"example", b"com"],
+                qtype: RRType::CNAME as u16,
+                qclass: RRClass::IN as u16,
+            }],
+            vec![Answer::new(
+                vec![b"WWW", b"Example", b"COM"],
+                RRClass::IN,
+                60,
+                RData::CNAME(vec![b"web", b"example", b"com"]),
+            )],
+        );
+        hdr.authorities.push(Answer::new(
+            vec![b"example", b"com"],
+            RRClass::IN,
+            60,
+            RData::MX {
+                preference: 10,
+                exchange: vec![b"mail", b"example", b"com"],
+            },
+        ));
+        hdr.additionals.push(Answer::new(
+            vec![b"web", b"example", b"com"],
+            RRClass::IN,
+            60,
+            RData::SRV {
+                priority: 0,
+                weight: 0,
+                port: 80,
+                target: vec![b"example", b"com"],
+            },
+        ));
+
+        let bytes = hdr.to_bytes();
+
+        // the answer owner name is a single pointer to the question name
+        let owner = DNS_HDR_SIZE + 17 + 4;
+        assert_eq!(&bytes[owner..owner + 2], &[0xc0, DNS_HDR_SIZE as u8]);
+        // the CNAME target only writes "web" before pointing at example.com
+        let rdata = owner + 2 + 10;
+        assert_eq!(&bytes[rdata..rdata + 6], &[3, b'w', b'e', b'b', 0xc0, 16]);
+        // SRV targets are never compressed
+        assert!(
+            bytes.ends_with(&[7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0])
+        );
+
+        let decoded = DNSHdr::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.queries[0].name, hdr.queries[0].name);
+        assert_eq!(
+            decoded.answers[0].name,
+            vec![b"www".as_slice(), b"example", b"com"]
+        );
+        assert_eq!(decoded.answers[0].rdata, hdr.answers[0].rdata);
+        assert_eq!(decoded.authorities[0].name, hdr.authorities[0].name);
+        assert_eq!(decoded.authorities[0].rdata, hdr.authorities[0].rdata);
+        assert_eq!(decoded.additionals[0].name, hdr.additionals[0].name);
+        assert_eq!(decoded.additionals[0].rdata, hdr.additionals[0].rdata);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_name_compression_many_answers() -> Result<()> {
+        let flags = Flags {
+            qr: 1,
+            opcode: 0,
+            aa: 0,
+            tc: 0,
+            rd: 0,
+            ra: 0,
+            rcode: 0,
+        };
+        let name: Vec<&[u8]> = vec![b"a-rather-long-host-name", b"example", b"com"];
+        let answers = (0..20)
+            .map(|i| {
+                Answer::new(
+                    name.clone(),
+                    RRClass::IN,
+                    60,
+                    RData::A(Ipv4Addr::new(10, 0, 0, i)),
+                )
+            })
+            .collect::<Vec<_>>();
+        let hdr = DNSHdr::new(
+            1,
+            flags,
+            vec![Query {
+                name: name.clone(),
+                qtype: RRType::A as u16,
+                qclass: RRClass::IN as u16,
+            }],
+            answers,
+        );
+
+        let bytes = hdr.to_bytes();
+        // 12 header + 41 question + 20 * (2 pointer + 10 fixed + 4 address)
+        assert_eq!(bytes.len(), DNS_HDR_SIZE + 41 + 20 * 16);
+
+        let decoded = DNSHdr::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.answers.len(), 20);
+        assert!(decoded.answers.iter().all(|a| a.name == name));
+        assert_eq!(
+            decoded.answers[19].rdata,
+            RData::A(Ipv4Addr::new(10, 0, 0, 19))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_name_pointer_to_itself() {
+        let buf: &[u8] = &[0, 0, 0xc0, 2];
+        assert_eq!(
+            decode_name(&buf[2..], buf),
+            Err(NameError::ForwardPointer(2))
+        );
+    }
+
+    #[test]
+    fn test_name_forward_pointer() {
+        let buf: &[u8] = &[0xc0, 3, 0, 1, b'a', 0];
+        assert_eq!(decode_name(buf, buf), Err(NameError::ForwardPointer(3)));
+    }
+
+    #[test]
+    fn test_name_pointer_loop() {
+        // "a" at 2 followed by a pointer back to 2
+        let buf: &[u8] = &[0, 0, 1, b'a', 0xc0, 2];
+        assert_eq!(decode_name(&buf[2..], buf), Err(NameError::PointerLoop(2)));
+
+        // 2: "a" -> ptr 8, 8: "b" -> ptr 2; entered through a pointer at 12 the
+        // cycle is caught where it has to jump forwards again
+        let buf: &[u8] = &[0, 0, 1, b'a', 0xc0, 8, 0, 0, 1, b'b', 0xc0, 2, 0xc0, 8];
+        assert_eq!(
+            decode_name(&buf[12..], buf),
+            Err(NameError::ForwardPointer(8))
+        );
+    }
+
+    #[test]
+    fn test_name_label_limits() {
+        let mut buf = vec![64];
+        buf.extend([b'a'; 64]);
+        buf.push(0);
+        assert_eq!(decode_name(&buf, &buf), Err(NameError::LabelTooLong(64)));
+
+        let buf: &[u8] = &[0x80, 0];
+        assert_eq!(decode_name(buf, buf), Err(NameError::BadLabelType(0x80)));
+
+        let mut buf = vec![];
+        for _ in 0..4 {
+            buf.push(63);
+            buf.extend([b'a'; 63]);
+        }
+        buf.push(0);
+        assert_eq!(decode_name(&buf, &buf), Err(NameError::NameTooLong));
+
+        // 3 * 64 + 62 + 1 = 255 bytes is still valid
+        let mut buf = vec![];
+        for _ in 0..3 {
+            buf.push(63);
+            buf.extend([b'a'; 63]);
+        }
+        buf.push(61);
+        buf.extend([b'a'; 61]);
+        buf.push(0);
+        assert_eq!(buf.len(), 255);
+        assert_eq!(decode_name(&buf, &buf).unwrap().1.len(), 4);
+    }
+
+    #[test]
+    fn test_name_too_long_through_pointers() {
+        // a 128 byte name at 0, then two labels of 63 prefixed to a pointer at it
+        let mut buf = vec![];
+        for _ in 0..2 {
+            buf.push(63);
+            buf.extend([b'a'; 63]);
+        }
+        buf.push(0);
+        let start = buf.len();
+        for _ in 0..2 {
+            buf.push(63);
+            buf.extend([b'b'; 63]);
+        }
+        buf.extend([0xc0, 0]);
+        assert_eq!(
+            decode_name(&buf[start..], &buf),
+            Err(NameError::NameTooLong)
+        );
+    }
+
+    #[test]
+    fn test_name_bounded_by_buf() {
+        // the name in buf is cut short even though pkt carries on
+        let pkt: &[u8] = &[1, b'a', 1, b'b', 0];
+        assert_eq!(decode_name(&pkt[..3], pkt), Err(NameError::Truncated));
+
+        let (rest, labels) = decode_name(&pkt[2..], pkt).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(labels, vec![b"b".as_slice()]);
+    }
+
+    #[test]
+    fn test_name_truncated() {
+        let buf: &[u8] = &[3, b'a', b'b'];
+        assert_eq!(decode_name(buf, buf), Err(NameError::Truncated));
+
+        let buf: &[u8] = &[1, b'a'];
+        assert_eq!(decode_name(buf, buf), Err(NameError::Truncated));
+
+        // pointer missing its second byte
+        let buf: &[u8] = &[1, b'a', 0xc0];
+        assert_eq!(decode_name(buf, buf), Err(NameError::Truncated));
+    }
+
+    #[test]
+    fn test_hostile_packet_rejected() {
+        // header with one question whose name points at itself
+        let buf: &[u8] = &[0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 12, 0, 1, 0, 1];
+        assert_eq!(
+            DNSHdr::from_bytes(buf).unwrap_err(),
+            WireError::PointerLoop(NameError::ForwardPointer(12))
+        );
+    }
+
+    #[test]
+    fn test_wire_errors() {
+        assert_eq!(
+            DNSHdr::from_bytes(&[0, 1, 1, 0, 0]).unwrap_err(),
+            WireError::TruncatedHeader(5)
+        );
+
+        // a label type that does not exist
+        let buf: &[u8] = &[0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x80, 0, 1, 0, 1];
+        assert_eq!(
+            DNSHdr::from_bytes(buf).unwrap_err(),
+            WireError::BadLabel(NameError::BadLabelType(0x80))
+        );
+
+        // two questions announced, one present
+        let buf: &[u8] = &[0, 1, 1, 0, 0, 2, 0, 0, 0, 0, 0, 0, 1, b'a', 0, 0, 1, 0, 1];
+        assert_eq!(
+            DNSHdr::from_bytes(buf).unwrap_err(),
+            WireError::CountMismatch {
+                expected: 2,
+                found: 1
+            }
+        );
+
+        // the question stops after its QTYPE
+        let buf: &[u8] = &[0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, b'a', 0, 0, 1];
+        assert_eq!(DNSHdr::from_bytes(buf).unwrap_err(), WireError::Truncated);
+
+        let buf: &[u8] = &[
+            0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, b'a', 0, 0, 1, 0, 1, 0,
+        ];
+        assert_eq!(
+            DNSHdr::from_bytes(buf).unwrap_err(),
+            WireError::TrailingBytes(1)
+        );
+    }
+
+    #[test]
+    fn test_rdata_overrun() {
+        // RDLENGTH of 4 with only two bytes left in the packet
+        let buf: &[u8] = &[
+            0, 1, 129, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0,
+        ];
+        assert_eq!(
+            DNSHdr::from_bytes(buf).unwrap_err(),
+            WireError::RdataOverrun {
+                rtype: RRType::A as u16,
+                rdlength: 4
+            }
+        );
+
+        // a CNAME whose target runs past its RDLENGTH
+        let buf: &[u8] = &[
+            0, 1, 129, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 1, b'a', 0,
+        ];
+        assert_eq!(
+            DNSHdr::from_bytes(buf).unwrap_err(),
+            WireError::RdataOverrun {
+                rtype: RRType::CNAME as u16,
+                rdlength: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_edns_roundtrip() -> Result<()> {
+        let flags = Flags {
+            qr: 0,
+            opcode: 0,
+            aa: 0,
+            tc: 0,
+            rd: 1,
+            ra: 0,
+            rcode: 0,
+        };
+        let mut hdr = DNSHdr::new(
+            7,
+            flags,
+            vec![Query {
+                name: vec![b"example", b"com"],
+                qtype: RRType::A as u16,
+                qclass: RRClass::IN as u16,
+            }],
+            vec![],
+        );
+        hdr.additionals.push(Answer::new(
+            vec![b"ns", b"example", b"com"],
+            RRClass::IN,
+            60,
+            RData::A(Ipv4Addr::new(10, 0, 0, 1)),
+        ));
+        let mut edns = Edns::new(1232);
+        edns.dnssec_ok = true;
+        edns.options.push((10, b"cookie!!"));
+        hdr.edns = Some(edns.clone());
+
+        let bytes = hdr.to_bytes();
+        assert_eq!(&bytes[10..12], &[0, 2]);
+
+        let decoded = DNSHdr::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.additionals.len(), 1);
+        assert_eq!(decoded.edns, Some(edns));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_edns_extended_rcode() {
+        let flags = Flags {
+            qr: 1,
+            opcode: 0,
+            aa: 0,
+            tc: 0,
+            rd: 0,
+            ra: 0,
+            rcode: 0,
+        };
+        let mut hdr = DNSHdr::new(7, flags, vec![], vec![]);
+        hdr.edns = Some(Edns::new(512));
+        hdr.set_rcode(RCode::BadVers as u16);
+        assert_eq!(hdr.flags.rcode, 0);
+
+        let bytes = hdr.to_bytes();
+        let decoded = DNSHdr::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.edns.as_ref().unwrap().extended_rcode, 1);
+        assert_eq!(decoded.rcode(), RCode::BadVers as u16);
+    }
+
+    #[test]
+    fn test_edns_bad_opt() {
+        // two OPT records in the additional section
+        let buf: &[u8] = &[
+            0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 41, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 41, 2, 0,
+            0, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(DNSHdr::from_bytes(buf).unwrap_err(), WireError::BadOpt);
+
+        // an option whose length runs past the RDATA
+        let buf: &[u8] = &[
+            0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 41, 2, 0, 0, 0, 0, 0, 0, 4, 0, 10, 0, 8,
+        ];
+        assert_eq!(DNSHdr::from_bytes(buf).unwrap_err(), WireError::BadOpt);
+    }
+
+    fn a_records(name: Vec<&[u8]>, n: u8) -> Vec<Answer<'_>> {
+        (0..n)
+            .map(|i| {
+                Answer::new(
+                    name.clone(),
+                    RRClass::IN,
+                    60,
+                    RData::A(Ipv4Addr::new(10, 0, 0, i)),
+                )
+            })
+            .collect()
+    }
+
+    fn response(answers: Vec<Answer<'_>>) -> DNSHdr<'_> {
+        let flags = Flags {
+            qr: 1,
+            opcode: 0,
+            aa: 0,
+            tc: 0,
+            rd: 0,
+            ra: 0,
+            rcode: 0,
+        };
+        DNSHdr::new(
+            1,
+            flags,
+            vec![Query {
+                name: vec![b"big", b"example", b"com"],
+                qtype: RRType::A as u16,
+                qclass: RRClass::IN as u16,
+            }],
+            answers,
+        )
+    }
+
+    #[test]
+    fn test_truncate_fits() {
+        let mut hdr = response(a_records(vec![b"big", b"example", b"com"], 5));
+        let bytes = hdr.truncate_to(512);
+        assert_eq!(bytes, hdr.to_bytes());
+        assert_eq!(hdr.flags.tc, 0);
+        assert_eq!(hdr.answers.len(), 5);
+    }
+
+    #[test]
+    fn test_truncate_drops_whole_rrsets() {
+        let mut answers = a_records(vec![b"small", b"example", b"com"], 2);
+        answers.extend(a_records(vec![b"big", b"example", b"com"], 100));
+        let mut hdr = response(answers);
+
+        let bytes = hdr.truncate_to(512);
+        assert!(bytes.len() <= 512);
+
+        let decoded = DNSHdr::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.flags.tc, 1);
+        assert_eq!(decoded.queries.len(), 1);
+        assert_eq!(decoded.answers.len(), 2);
+        assert!(decoded
+            .answers
+            .iter()
+            .all(|a| a.name == vec![b"small".as_slice(), b"example", b"com"]));
+    }
+
+    #[test]
+    fn test_truncate_additional_without_tc() {
+        let mut hdr = response(a_records(vec![b"big", b"example", b"com"], 4));
+        hdr.additionals = a_records(vec![b"glue", b"example", b"com"], 100);
+        hdr.edns = Some(Edns::new(512));
+
+        let bytes = hdr.truncate_to(512);
+        assert!(bytes.len() <= 512);
+
+        let decoded = DNSHdr::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.flags.tc, 0);
+        assert_eq!(decoded.answers.len(), 4);
+        assert!(decoded.additionals.is_empty());
+        assert!(decoded.edns.is_some());
+    }
+
+    #[test]
+    fn test_truncate_larger_limit() {
+        let mut hdr = response(a_records(vec![b"big", b"example", b"com"], 100));
+        let size = hdr.to_bytes().len();
+        assert!(size > 512);
+
+        let bytes = hdr.truncate_to(512);
+        assert!(bytes.len() <= 512);
+        assert_eq!(hdr.flags.tc, 1);
+        assert!(hdr.answers.is_empty());
+
+        let mut hdr = response(a_records(vec![b"big", b"example", b"com"], 100));
+        let bytes = hdr.truncate_to(4096);
+        assert_eq!(bytes.len(), size);
+        assert_eq!(hdr.flags.tc, 0);
+    }
 }
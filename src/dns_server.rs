@@ -1,205 +1,236 @@
-use crate::dns_hdr::{Answer, DNSHdr, Flags, OpCode, Query, RCode, RRClass, RRType};
+use crate::authority::{Lookup, Zone, Zones};
+use crate::cache::{Cache, Cached};
+use crate::dns_hdr::{
+    Answer, DNSHdr, Edns, Flags, Query, RCode, RData, RRClass, RRType, EDNS_UDP_SIZE,
+    MAX_CNAME_CHAIN, QCLASS_ANY, QTYPE_ANY,
+};
+use crate::name::DomainName;
+use crate::recursor::Recursor;
+use crate::resolver::Resolver;
+use crate::tcp::{read_message, write_message};
+use crate::zone::{self, Record};
 use anyhow::{Context, Result};
-use rand::Rng;
+use bytes::Bytes;
 use std::collections::HashMap;
-use std::net::{Ipv4Addr, UdpSocket};
+use std::io::ErrorKind;
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
-struct Resolver {
-    socket: UdpSocket,
+// Largest UDP message without EDNS (RFC 1035 4.2.1)
+const MAX_UDP_SIZE: usize = 512;
+const EDNS_VERSION: u8 = 0;
+// How long a TCP client may stay silent before we close the connection
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+// UDP queries and TCP connections served at once, anything over is dropped
+const MAX_UDP_WORKERS: usize = 256;
+const MAX_TCP_CONNECTIONS: usize = 64;
+// Bytes of forwarded RRsets we keep around
+const CACHE_SIZE: usize = 4 << 20;
+// Origin, file name and contents of the zones served without --zone
+const DEFAULT_ZONES: [(&str, &str, &str); 2] = [
+    (
+        "codecrafters.io",
+        "codecrafters.io.zone",
+        include_str!("../zones/codecrafters.io.zone"),
+    ),
+    (
+        "stackoverflow.com",
+        "stackoverflow.com.zone",
+        include_str!("../zones/stackoverflow.com.zone"),
+    ),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    Udp,
+    Tcp,
 }
 
-impl Resolver {
-    fn new(addr: &str) -> Result<Self> {
-        let udp_socket = UdpSocket::bind("0.0.0.0:0").context("Failed to bind to address")?;
-        udp_socket.connect(addr)?;
+// Where questions for names outside our zones go
+pub enum Upstream {
+    Forward(Resolver),
+    Recursive(Recursor),
+}
 
-        Ok(Self { socket: udp_socket })
+impl Upstream {
+    // The response message for the question, whatever its RCODE
+    fn resolve(&self, query: &Query) -> Result<Vec<u8>> {
+        match self {
+            Upstream::Forward(resolver) => resolver.resolve(query),
+            Upstream::Recursive(recursor) => recursor.resolve(query),
+        }
     }
+}
 
-    fn resolve_a(&mut self, domain: Vec<&[u8]>) -> Result<(u32, Ipv4Addr)> {
-        let mut rng = rand::thread_rng();
+/*
+Conditional forwarding: names at or below a domain in the table go to the
+resolver of the longest such domain, anything else to the default upstream.
+*/
+#[derive(Default)]
+struct Upstreams {
+    forwarders: HashMap<DomainName, Resolver>,
+    default: Option<Upstream>,
+}
 
-        // create a dns request
-        let id = rng.gen();
-        let flags = Flags {
-            qr: 0,
-            opcode: OpCode::QUERY as u8,
-            aa: 0,
-            tc: 0,
-            rd: 0,
-            ra: 0,
-            rcode: 0,
-        };
-        let query = Query {
-            name: domain,
-            qtype: RRType::A as u16,
-            qclass: RRClass::IN as u16,
-        };
-        let req = DNSHdr::new(id, flags, vec![query], vec![]);
-        eprintln!("Sending {req:?}");
+impl Upstreams {
+    fn covers(&self, name: &DomainName) -> bool {
+        self.default.is_some() || name.ancestors().any(|d| self.forwarders.contains_key(&d))
+    }
 
-        // send to resolver
-        self.socket.send(&req.to_bytes())?;
+    fn resolve(&self, query: &Query) -> Result<Vec<u8>> {
+        let domain = query.domain();
+        match domain.ancestors().find_map(|d| self.forwarders.get(&d)) {
+            Some(resolver) => resolver.resolve(query),
+            None => match &self.default {
+                Some(upstream) => upstream.resolve(query),
+                None => anyhow::bail!("No upstream for {domain}"),
+            },
+        }
+    }
+}
 
-        // wait for response and parse addr
-        let mut buf = [0; 512];
+/*
+Shared by every connection. Zones and upstreams are only written while the
+server is being set up, so queries just read them. The cache is locked to
+look things up and again to store what came back, never while a question is
+out with an upstream.
+*/
+struct QueryHandler {
+    zones: RwLock<Zones>,
+    // RRsets learned from the resolver, kept apart from our own zones
+    cache: Mutex<Cache>,
+    upstreams: RwLock<Upstreams>,
+}
 
-        let (size, source) = self.socket.recv_from(&mut buf)?;
+pub struct DNSServer {
+    socket: UdpSocket,
+    listener: TcpListener,
+    handler: Arc<QueryHandler>,
+    tcp_idle_timeout: Duration,
+    max_udp_workers: usize,
+    max_tcp_connections: usize,
+}
 
-        println!("Received {} bytes from {} {:?}", size, source, &buf[..size]);
-        let answer = &buf[..size];
-        if let Ok((_, answer)) = DNSHdr::from_bytes(&answer) {
-            eprintln!(
-                "Received DNS answer: {} {} {:?} ", answer.queries.len(), answer.answers.len(),
-                answer
-                    .answers
-                    .iter()
-                    .map(|a| format!(
-                        "{:?} ttl={} qclass={} qtype={}",
-                        a.rddata, a.ttl, a.qclass, a.qtype
-                    ))
-                    .collect::<Vec<_>>()
-            );
+/*
+Caps how many threads serve queries at once, so that a burst of queries
+waiting on a slow upstream cannot use up threads and file descriptors. A
+worker holds a slot for as long as it runs.
+*/
+#[derive(Clone)]
+struct Workers {
+    active: Arc<AtomicUsize>,
+    max: usize,
+}
 
-            let rddata = answer.answers[0].rddata;
-            let ttl = answer.answers[0].ttl;
-            let ip = Ipv4Addr::new(rddata[0], rddata[1], rddata[2], rddata[3]);
+struct Slot(Arc<AtomicUsize>);
 
-            Ok((ttl, ip))
-        } else {
-            anyhow::bail!("Resolver failed")
+impl Workers {
+    fn new(max: usize) -> Self {
+        Workers {
+            active: Arc::new(AtomicUsize::new(0)),
+            max,
         }
     }
+
+    // Runs f on a thread of its own, unless all workers are busy
+    fn spawn(&self, f: impl FnOnce() + Send + 'static) -> Result<()> {
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < self.max).then_some(n + 1)
+            })
+            .map_err(|_| anyhow::anyhow!("all {} workers are busy", self.max))?;
+        let slot = Slot(self.active.clone());
+
+        thread::Builder::new().spawn(move || {
+            let _slot = slot;
+            f();
+        })?;
+
+        Ok(())
+    }
 }
 
-pub struct DNSServer {
-    socket: UdpSocket,
-    rr_db: HashMap<String, (u32, [u8; 4])>,
-    resolver: Option<Resolver>,
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl DNSServer {
-    pub fn new(addr: &str, resolver: Option<String>) -> Result<Self> {
+    pub fn new(addr: &str, resolver: Option<Upstream>) -> Result<Self> {
         let udp_socket = UdpSocket::bind(addr).context("Failed to bind to address")?;
+        // bind TCP to the port UDP actually got, in case addr asked for any port
+        let listener =
+            TcpListener::bind(udp_socket.local_addr()?).context("Failed to bind to address")?;
 
         Ok(Self {
             socket: udp_socket,
-            rr_db: HashMap::from([
-                (
-                    "codecrafters.io".to_string(),
-                    (60, Ipv4Addr::new(192, 168, 10, 10).octets()),
-                ),
-                (
-                    "stackoverflow.com".to_string(),
-                    (60, Ipv4Addr::new(192, 168, 10, 20).octets()),
-                ),
-            ]),
-            resolver: resolver.map(|addr| Resolver::new(&addr).expect(&format!("invalid {addr:?}"))),
+            listener,
+            handler: Arc::new(QueryHandler {
+                zones: RwLock::new(Zones::default()),
+                cache: Mutex::new(Cache::new(CACHE_SIZE)),
+                upstreams: RwLock::new(Upstreams {
+                    forwarders: HashMap::new(),
+                    default: resolver,
+                }),
+            }),
+            tcp_idle_timeout: TCP_IDLE_TIMEOUT,
+            max_udp_workers: MAX_UDP_WORKERS,
+            max_tcp_connections: MAX_TCP_CONNECTIONS,
         })
     }
 
-    pub fn start(&mut self) {
-        let mut buf = [0; 512];
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn start(&self) {
+        match self.listener.try_clone() {
+            Ok(listener) => {
+                let handler = self.handler.clone();
+                let workers = Workers::new(self.max_tcp_connections);
+                let idle_timeout = self.tcp_idle_timeout;
+                thread::spawn(move || accept_tcp(listener, handler, workers, idle_timeout));
+            }
+            Err(e) => eprintln!("Not serving TCP: {e}"),
+        }
+
+        if let Ok(addr) = self.local_addr() {
+            println!("Listening on {addr}");
+        }
+
+        let workers = Workers::new(self.max_udp_workers);
+        let mut buf = [0; EDNS_UDP_SIZE as usize];
 
         loop {
             match self.socket.recv_from(&mut buf) {
                 Ok((size, source)) => {
                     println!("Received {} bytes from {} {:?}", size, source, &buf[..size]);
-                    let req = &buf[..size];
-                    if let Ok((_, request)) = DNSHdr::from_bytes(&req) {
-                        eprintln!(
-                            "Received DNS query: {:?} ",
-                            request
-                                .queries
-                                .iter()
-                                .map(|q| format!("{} {} {}", q.domain(), q.qclass, q.qtype))
-                                .collect::<Vec<_>>()
-                        );
-
-                        let response = match request.flags.opcode {
-                            0 => {
-                                let answs = match &mut self.resolver {
-                                    None => request
-                                        .queries
-                                        .iter()
-                                        .filter_map(|q| {
-                                            self.rr_db.get("codecrafters.io").map(|(ttl, data)| {
-                                                Answer::new(
-                                                    q.name.clone(),
-                                                    RRType::A,
-                                                    RRClass::IN,
-                                                    *ttl,
-                                                    data,
-                                                )
-                                            })
-                                        })
-                                        .collect::<Vec<_>>(),
-                                    Some(resolver) => {
-                                        
-                                        let ans = request
-                                            .queries
-                                            .iter()
-                                            .filter(|q| !self.rr_db.contains_key(&q.domain()))
-                                            .map(|q| (q, resolver.resolve_a(q.name.clone()).map(|(ttl, ip)| (ttl, ip.octets())).unwrap()))
-                                            .collect::<Vec<_>>();
-
-                                        self.rr_db.extend(ans.iter().map(|(q, (ttl, ip))| (q.domain(), (*ttl, *ip))));
-
-                                        request
-                                        .queries
-                                        .iter()
-                                        .filter_map(|q| {
-                                            self.rr_db.get(&q.domain()).map(|(ttl, data)| {
-                                                Answer::new(
-                                                    q.name.clone(),
-                                                    RRType::A,
-                                                    RRClass::IN,
-                                                    *ttl,
-                                                    data,
-                                                )
-                                            })
-                                        })
-                                        .collect::<Vec<_>>()
-
-                                        
+                    // a worker per query, so that one waiting on an upstream
+                    // holds up nobody else; the client retries if we drop it
+                    let served = self
+                        .socket
+                        .try_clone()
+                        .map_err(Into::into)
+                        .and_then(|socket| {
+                            let handler = self.handler.clone();
+                            let req = buf[..size].to_vec();
+                            workers.spawn(move || {
+                                if let Some(response) = handler.handle(&req, Transport::Udp) {
+                                    if let Err(e) = socket.send_to(&response, source) {
+                                        eprintln!("Error sending response to {source}: {e}");
                                     }
-                                };
-
-                                DNSHdr::new(
-                                    request.id,
-                                    Flags {
-                                        qr: 1,
-                                        aa: 0,
-                                        tc: 0,
-                                        ra: 0,
-                                        rcode: RCode::OK as u8,
-                                        ..request.flags
-                                    },
-                                    request.queries.clone(),
-                                    answs,
-                                )
-                                .to_bytes()
-                            }
-                            _ => DNSHdr::new(
-                                request.id,
-                                Flags {
-                                    qr: 1,
-                                    aa: 0,
-                                    tc: 0,
-                                    ra: 0,
-                                    rcode: RCode::NotImplemted as u8,
-                                    ..request.flags
-                                },
-                                request.queries.clone(),
-                                vec![],
-                            )
-                            .to_bytes(),
-                        };
-
-                        self.socket
-                            .send_to(&response, source)
-                            .expect("Failed to send response");
-                    };
+                                }
+                            })
+                        });
+                    if let Err(e) = served {
+                        eprintln!("Dropping query from {source}: {e:#}");
+                    }
                 }
                 Err(e) => {
                     eprintln!("Error receiving data: {}", e);
@@ -208,4 +239,1702 @@ impl DNSServer {
             }
         }
     }
+
+    // Serves the zone in a master file authoritatively
+    pub fn load_zone(&self, path: &Path, origin: DomainName) -> Result<()> {
+        let zone = Zone::new(origin.clone(), zone::load(path, &origin)?)?;
+        self.add_zone(zone);
+
+        Ok(())
+    }
+
+    // Serves the zones built into the binary, for when no zone is given
+    pub fn load_default_zones(&self) -> Result<()> {
+        for (origin, file, src) in DEFAULT_ZONES {
+            let origin: DomainName = origin.parse()?;
+            let zone = Zone::new(origin.clone(), zone::parse(src, file, &origin)?)?;
+            self.add_zone(zone);
+        }
+
+        Ok(())
+    }
+
+    pub fn add_zone(&self, zone: Zone) {
+        self.handler.zones.write().unwrap().add(zone);
+    }
+
+    // Forwards questions for names at or below domain to resolver, our own
+    // zones still take precedence
+    pub fn add_forwarder(&self, domain: DomainName, resolver: Resolver) {
+        let mut upstreams = self.handler.upstreams.write().unwrap();
+        upstreams.forwarders.insert(domain, resolver);
+    }
+}
+
+fn accept_tcp(
+    listener: TcpListener,
+    handler: Arc<QueryHandler>,
+    workers: Workers,
+    idle_timeout: Duration,
+) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let handler = handler.clone();
+                // the connection is closed when it is dropped unserved
+                let served = workers.spawn(move || {
+                    if let Err(e) = serve_tcp(stream, &handler, idle_timeout) {
+                        eprintln!("Error serving TCP connection: {e}");
+                    }
+                });
+                if let Err(e) = served {
+                    eprintln!("Closing TCP connection: {e:#}");
+                }
+            }
+            Err(e) => eprintln!("Error accepting connection: {e}"),
+        }
+    }
+}
+
+/*
+DNS over TCP (RFC 1035 4.2.2, RFC 7766): every message is prefixed with its
+length as a two byte integer. Queries are answered in the order they arrive,
+so clients may pipeline several before reading the first response. The
+connection is closed once the client has been idle for idle_timeout.
+*/
+fn serve_tcp(mut stream: TcpStream, handler: &QueryHandler, idle_timeout: Duration) -> Result<()> {
+    stream.set_read_timeout(Some(idle_timeout))?;
+
+    loop {
+        let req = match read_message(&mut stream) {
+            Ok(req) => req,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::UnexpectedEof | ErrorKind::WouldBlock | ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if let Some(response) = handler.handle(&req, Transport::Tcp) {
+            write_message(&mut stream, &response)?;
+        }
+    }
+}
+
+impl QueryHandler {
+    fn handle(&self, req: &[u8], transport: Transport) -> Option<Bytes> {
+        // answering a response could start a loop with whoever sent it, so
+        // it is dropped, even if it would not decode (RFC 1035 4.1.1)
+        if is_response(req) {
+            eprintln!("Dropping DNS response sent as a query");
+            return None;
+        }
+
+        let request = match DNSHdr::from_bytes(req) {
+            Ok(request) => request,
+            Err(e) => {
+                eprintln!("Malformed DNS query: {e}");
+                return format_error(req);
+            }
+        };
+
+        eprintln!(
+            "Received DNS query: {:?} ",
+            request
+                .queries
+                .iter()
+                .map(|q| format!("{} {} {}", q.domain(), q.qclass, q.qtype))
+                .collect::<Vec<_>>()
+        );
+
+        // our zones, upstream responses and cached RRsets, the records in
+        // the response are borrowed from them
+        let zones = self.zones.read().unwrap();
+        let upstreams = self.upstreams.read().unwrap();
+        let mut upstream = vec![];
+        let mut hits = vec![];
+        let mut response = DNSHdr::new(
+            request.id,
+            Flags {
+                qr: 1,
+                aa: 0,
+                tc: 0,
+                ra: 0,
+                rcode: RCode::OK as u8,
+                ..request.flags
+            },
+            request.queries.clone(),
+            vec![],
+        );
+        response.edns = request.edns.as_ref().map(|_| Edns::new(EDNS_UDP_SIZE));
+        // recursion is available for the names we have an upstream for
+        response.flags.ra = request
+            .queries
+            .iter()
+            .any(|q| upstreams.covers(&q.domain())) as u8;
+
+        match (&request.edns, request.flags.opcode) {
+            (Some(edns), _) if edns.version != EDNS_VERSION => {
+                response.set_rcode(RCode::BadVers as u16);
+            }
+            (_, 0) => {
+                let now = Instant::now();
+
+                // names in our zones are never forwarded
+                let (local, others): (Vec<_>, Vec<_>) = request.queries.iter().partition(|q| {
+                    let domain = q.domain();
+                    zones.find(&domain).is_some() || !upstreams.covers(&domain)
+                });
+
+                local
+                    .iter()
+                    .for_each(|q| answer_locally(&zones, q, &mut response));
+
+                let mut forward = vec![];
+                let mut cache = self.cache.lock().unwrap();
+                for q in others {
+                    match cached_chain(&mut cache, q, now) {
+                        Some(chain) => hits.push((q, chain)),
+                        None => forward.push(q),
+                    }
+                }
+                drop(cache);
+                for (q, chain) in &hits {
+                    answer_from_cache(q, chain, &mut response);
+                }
+
+                if !forward.is_empty() {
+                    // a question the upstream failed on does not cost the
+                    // others their answers
+                    upstream = forward
+                        .iter()
+                        .map(|q| {
+                            upstreams
+                                .resolve(q)
+                                .map_err(|e| eprintln!("Resolver failed for {}: {e:#}", q.domain()))
+                                .ok()
+                        })
+                        .collect();
+
+                    for (q, bytes) in forward.iter().zip(upstream.iter()) {
+                        let Some(Ok(mut forwarded)) = bytes.as_deref().map(DNSHdr::from_bytes)
+                        else {
+                            set_error(&mut response, RCode::ServerFailure);
+                            continue;
+                        };
+
+                        // the first upstream error is passed back unchanged
+                        if response.rcode() == RCode::OK as u16 {
+                            response.set_rcode(forwarded.rcode());
+                        }
+
+                        forwarded.answers = answer_chain(q, mem::take(&mut forwarded.answers));
+                        let mut rrsets: HashMap<_, Vec<_>> = HashMap::new();
+                        for a in &forwarded.answers {
+                            rrsets
+                                .entry((DomainName::from_labels(&a.name), a.qtype, a.qclass))
+                                .or_default()
+                                .push(Record::new(a.ttl, &a.rdata));
+                        }
+                        let mut cache = self.cache.lock().unwrap();
+                        for ((name, rtype, class), records) in rrsets {
+                            cache.insert(name, rtype, class, Cached::Answer(records), now);
+                        }
+                        // no answer to ANY says nothing about any one type
+                        if let Some(negative) =
+                            negative_answer(&forwarded).filter(|_| q.qtype != QTYPE_ANY)
+                        {
+                            cache.insert(q.domain(), q.qtype, q.qclass, negative, now);
+                        }
+
+                        response.answers.extend(forwarded.answers);
+                        response.authorities.extend(forwarded.authorities);
+                        response.additionals.extend(forwarded.additionals);
+                    }
+                }
+            }
+            _ => response.set_rcode(RCode::NotImplemted as u16),
+        }
+
+        let limit = match transport {
+            Transport::Udp => payload_limit(&request),
+            Transport::Tcp => u16::MAX as usize,
+        };
+        Some(response.truncate_to(limit))
+    }
+}
+
+/*
+What the cache knows about a question, following cached CNAMEs from the name
+asked for the way the upstream would have (RFC 1034 4.3.2). Every link of the
+chain is paired with its owner. Nothing is returned unless the whole chain is
+cached, down to an answer or a negative answer.
+*/
+fn cached_chain(
+    cache: &mut Cache,
+    query: &Query,
+    now: Instant,
+) -> Option<Vec<(DomainName, Cached)>> {
+    let mut owner = query.domain();
+    let mut chain = vec![];
+
+    loop {
+        if let Some(hit) = cache.get(&owner, query.qtype, query.qclass, now) {
+            chain.push((owner, hit));
+            return Some(chain);
+        }
+        if query.qtype == RRType::CNAME as u16 || chain.len() >= MAX_CNAME_CHAIN {
+            return None;
+        }
+
+        let Some(Cached::Answer(cname)) =
+            cache.get(&owner, RRType::CNAME as u16, query.qclass, now)
+        else {
+            return None;
+        };
+        let RData::CNAME(target) = cname.first()?.rdata() else {
+            return None;
+        };
+        let next = DomainName::from_labels(&target);
+        chain.push((owner, Cached::Answer(cname)));
+        owner = next;
+    }
+}
+
+// Answers a question from a chain found in the cache. The first owner is
+// given the case the client asked with.
+fn answer_from_cache<'a>(
+    query: &Query<'a>,
+    chain: &'a [(DomainName, Cached)],
+    response: &mut DNSHdr<'a>,
+) {
+    let record = |name, ttl, rdata| Answer {
+        qclass: query.qclass,
+        ..Answer::new(name, RRClass::IN, ttl, rdata)
+    };
+
+    for (i, (owner, hit)) in chain.iter().enumerate() {
+        let name = match i {
+            0 => query.name.clone(),
+            _ => owner.labels(),
+        };
+        match hit {
+            Cached::Answer(rrset) => response
+                .answers
+                .extend(rrset.iter().map(|r| record(name.clone(), r.ttl, r.rdata()))),
+            Cached::NoData { zone, soa } | Cached::NxDomain { zone, soa } => {
+                if matches!(hit, Cached::NxDomain { .. }) {
+                    set_error(response, RCode::NameError);
+                }
+                response
+                    .authorities
+                    .push(record(zone.labels(), soa.ttl, soa.rdata()));
+            }
+        }
+    }
+}
+
+/*
+The records of an upstream answer section that answer query: the RRset at the
+name asked for, or the CNAMEs leading away from it and the RRset at the end of
+the chain. Anything else was not asked for and could poison the cache with
+names the upstream has no say over, so it is dropped.
+*/
+fn answer_chain<'a>(query: &Query, answers: Vec<Answer<'a>>) -> Vec<Answer<'a>> {
+    let mut owner = query.domain();
+    let mut rest = answers;
+    let mut chain = vec![];
+
+    for _ in 0..=MAX_CNAME_CHAIN {
+        let (at_owner, others): (Vec<_>, Vec<_>) = rest
+            .into_iter()
+            .partition(|a| a.qclass == query.qclass && DomainName::from_labels(&a.name) == owner);
+        rest = others;
+
+        let mut target = None;
+        for a in at_owner {
+            match &a.rdata {
+                RData::CNAME(next) if query.qtype != RRType::CNAME as u16 => {
+                    target = Some(DomainName::from_labels(next));
+                }
+                _ if a.qtype != query.qtype && query.qtype != QTYPE_ANY => continue,
+                _ => {}
+            }
+            chain.push(a);
+        }
+
+        match target {
+            Some(next) => owner = next,
+            None => break,
+        }
+    }
+
+    chain
+}
+
+/*
+The negative answer in an upstream response, if it is one we may cache:
+NXDOMAIN, or NOERROR without answers, with the SOA of the zone in the
+authority section (RFC 2308 2). Without the SOA there is no TTL to keep it
+for. A negative answer at the end of a CNAME chain is not kept, the chain is
+asked for again.
+*/
+fn negative_answer(response: &DNSHdr) -> Option<Cached> {
+    if !response.answers.is_empty() {
+        return None;
+    }
+
+    let soa = response
+        .authorities
+        .iter()
+        .find(|a| matches!(a.rdata, RData::SOA { .. }))?;
+    let zone = DomainName::from_labels(&soa.name);
+    let soa = Record::new(soa.ttl, &soa.rdata);
+
+    match response.rcode() {
+        rcode if rcode == RCode::NameError as u16 => Some(Cached::NxDomain { zone, soa }),
+        rcode if rcode == RCode::OK as u16 => Some(Cached::NoData { zone, soa }),
+        _ => None,
+    }
+}
+
+/*
+Answers a question we do not forward. Names in our zones are answered
+authoritatively: NXDOMAIN for a name that does not exist, an empty NOERROR
+(NODATA) for a name without records of the asked type, both with the zone SOA
+in the authority section and its TTL capped to the SOA minimum (RFC 2308 3).
+Names below a zone cut get a referral. Anything else is refused, as are
+classes other than IN since our zones hold nothing else.
+
+Aliases are followed through our zones, each CNAME going into the answer
+section ahead of whatever its target resolves to. The chain stops at the
+first target outside our zones, which is left to the client.
+*/
+fn answer_locally<'a>(zones: &'a Zones, query: &Query<'a>, response: &mut DNSHdr<'a>) {
+    let domain = query.domain();
+    let in_class = [RRClass::IN as u16, QCLASS_ANY].contains(&query.qclass);
+    let Some(mut zone) = zones.find(&domain).filter(|_| in_class) else {
+        set_error(response, RCode::Refused);
+        return;
+    };
+
+    let mut owner = query.name.clone();
+    let mut chain = vec![domain];
+    loop {
+        let domain = chain.last().unwrap();
+        let lookup = zone.lookup(domain, query.qtype);
+        // AA covers the name asked for, not the targets of aliases (RFC 6604 3)
+        if chain.len() == 1 {
+            response.flags.aa = !matches!(lookup, Lookup::Referral { .. }) as u8;
+        }
+
+        match lookup {
+            Lookup::Answer(rrset) => response.answers.extend(
+                rrset
+                    .into_iter()
+                    .map(|r| Answer::new(owner.clone(), RRClass::IN, r.ttl, r.rdata())),
+            ),
+            Lookup::Cname(cname) => {
+                let rdata = cname.rdata();
+                response
+                    .answers
+                    .push(Answer::new(owner, RRClass::IN, cname.ttl, rdata.clone()));
+
+                let RData::CNAME(target) = rdata else {
+                    unreachable!("CNAME records hold a CNAME");
+                };
+                let next = DomainName::from_labels(&target);
+                if chain.contains(&next) || chain.len() > MAX_CNAME_CHAIN {
+                    eprintln!("CNAME chain from {} loops or is too long", chain[0]);
+                    set_error(response, RCode::ServerFailure);
+                    return;
+                }
+
+                let Some(next_zone) = zones.find(&next) else {
+                    return;
+                };
+                zone = next_zone;
+                owner = target;
+                chain.push(next);
+                continue;
+            }
+            Lookup::NoData | Lookup::NxDomain => {
+                if lookup == Lookup::NxDomain {
+                    set_error(response, RCode::NameError);
+                }
+
+                let soa = zone.soa();
+                response.authorities.push(Answer::new(
+                    zone.origin().labels(),
+                    RRClass::IN,
+                    soa.negative_ttl(),
+                    soa.rdata(),
+                ));
+            }
+            // a target below a zone cut is left to the client, like any
+            // other target we are not authoritative for
+            Lookup::Referral { .. } if chain.len() > 1 => {}
+            Lookup::Referral { cut, ns, glue } => {
+                response.authorities.extend(
+                    ns.into_iter()
+                        .map(|r| Answer::new(cut.labels(), RRClass::IN, r.ttl, r.rdata())),
+                );
+                response
+                    .additionals
+                    .extend(glue.into_iter().map(|(name, r)| {
+                        Answer::new(name.labels(), RRClass::IN, r.ttl, r.rdata())
+                    }));
+            }
+        }
+
+        break;
+    }
+}
+
+// Only the first error is reported when there are several questions
+fn set_error(response: &mut DNSHdr, rcode: RCode) {
+    if response.rcode() == RCode::OK as u16 {
+        response.set_rcode(rcode as u16);
+    }
+}
+
+// The largest UDP response the client accepts: 512 bytes unless it advertises
+// more with EDNS, and never more than we advertise ourselves.
+fn payload_limit(request: &DNSHdr) -> usize {
+    request.edns.as_ref().map_or(MAX_UDP_SIZE, |e| {
+        (e.udp_payload_size as usize).clamp(MAX_UDP_SIZE, EDNS_UDP_SIZE as usize)
+    })
+}
+
+// Checks the QR bit straight from the header
+fn is_response(req: &[u8]) -> bool {
+    req.get(2).is_some_and(|flags_h| flags_h & 0b1000_0000 != 0)
+}
+
+// Answers a query we could not decode with FORMERR. Only the ID, opcode and RD
+// bit are echoed back since nothing past the header can be trusted; without
+// an ID there is nobody to answer.
+fn format_error(req: &[u8]) -> Option<Bytes> {
+    let id = u16::from_be_bytes([*req.first()?, *req.get(1)?]);
+    let flags_h = req.get(2).copied().unwrap_or(0);
+
+    Some(
+        DNSHdr::new(
+            id,
+            Flags {
+                qr: 1,
+                opcode: (flags_h >> 3) & 0b1111,
+                aa: 0,
+                tc: 0,
+                rd: flags_h & 1,
+                ra: 0,
+                rcode: RCode::FmtError as u8,
+            },
+            vec![],
+            vec![],
+        )
+        .to_bytes(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_hdr::{OpCode, RRType};
+    use crate::resolver::ResolverOptions;
+    use crate::testing::{mock_upstream, name, response, silent_resolver, silent_upstream};
+    use bytes::{BufMut, BytesMut};
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_format_error_echoes_id() {
+        // a query for a name whose compression pointer points at itself
+        let req: &[u8] = &[
+            0xab, 0xcd, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 12, 0, 1, 0, 1,
+        ];
+        assert!(DNSHdr::from_bytes(req).is_err());
+
+        let response = format_error(req).unwrap();
+        let response = DNSHdr::from_bytes(&response).unwrap();
+        assert_eq!(response.id, 0xabcd);
+        assert_eq!(response.flags.qr, 1);
+        assert_eq!(response.flags.rd, 1);
+        assert_eq!(response.flags.rcode, RCode::FmtError as u8);
+        assert!(response.queries.is_empty());
+    }
+
+    #[test]
+    fn test_format_error_needs_id() {
+        assert!(format_error(&[]).is_none());
+        assert!(format_error(&[0xab]).is_none());
+        assert!(format_error(&[0xab, 0xcd]).is_some());
+    }
+
+    #[test]
+    fn test_responses_dropped() -> Result<()> {
+        let server = test_server()?;
+
+        let mut req = query(1, vec![b"codecrafters", b"io"], None).to_vec();
+        req[2] |= 0b1000_0000;
+        assert!(server.handler.handle(&req, Transport::Udp).is_none());
+        assert!(server.handler.handle(&req, Transport::Tcp).is_none());
+
+        // not even a FORMERR for a response that does not decode
+        let req: &[u8] = &[0xab, 0xcd, 0b1000_0001, 0, 0, 1];
+        assert!(server.handler.handle(req, Transport::Udp).is_none());
+
+        Ok(())
+    }
+
+    const TEST_ZONES: [(&str, &str); 2] = [
+        (
+            "codecrafters.io",
+            "
+$TTL 60
+@       3600 IN SOA ns1 hostmaster 1 3600 600 86400 60
+        IN NS ns1
+        IN A 192.168.10.10
+ns1     IN A 192.168.10.1
+sub     IN NS ns.sub
+ns.sub  IN A 192.168.10.53
+www     IN CNAME @
+so      IN CNAME www.stackoverflow.com.
+ext     IN CNAME example.org.
+delegated IN CNAME www.sub
+gone    IN CNAME missing
+loop1   IN CNAME loop2
+loop2   IN CNAME loop1
+*.dev   IN A 192.168.10.30
+",
+        ),
+        (
+            "stackoverflow.com",
+            "
+$TTL 60
+@       3600 IN SOA ns1 hostmaster 1 3600 600 86400 60
+        IN A 192.168.10.20
+www     IN CNAME @
+",
+        ),
+    ];
+
+    fn test_server() -> Result<DNSServer> {
+        let server = DNSServer::new("127.0.0.1:0", None)?;
+        for (origin, src) in TEST_ZONES {
+            let origin: DomainName = origin.parse()?;
+            server.add_zone(Zone::new(
+                origin.clone(),
+                zone::parse(src, "test.zone", &origin)?,
+            )?);
+        }
+
+        Ok(server)
+    }
+
+    fn query(id: u16, name: Vec<&[u8]>, edns: Option<Edns>) -> Bytes {
+        let mut req = DNSHdr::new(
+            id,
+            Flags {
+                qr: 0,
+                opcode: OpCode::QUERY as u8,
+                aa: 0,
+                tc: 0,
+                rd: 1,
+                ra: 0,
+                rcode: 0,
+            },
+            vec![Query {
+                name,
+                qtype: RRType::A as u16,
+                qclass: RRClass::IN as u16,
+            }],
+            vec![],
+        );
+        req.edns = edns;
+        req.to_bytes()
+    }
+
+    #[test]
+    fn test_edns_echoed() -> Result<()> {
+        let server = test_server()?;
+
+        let mut edns = Edns::new(1232);
+        edns.options.push((10, b"cookie!!"));
+        let response = server.handler.handle(
+            &query(1, vec![b"codecrafters", b"io"], Some(edns)),
+            Transport::Udp,
+        );
+        let response = response.unwrap();
+        let response = DNSHdr::from_bytes(&response).unwrap();
+        assert_eq!(response.rcode(), RCode::OK as u16);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.edns, Some(Edns::new(EDNS_UDP_SIZE)));
+
+        let response = server.handler.handle(
+            &query(2, vec![b"codecrafters", b"io"], None),
+            Transport::Udp,
+        );
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        assert!(response.edns.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_edns_badvers() -> Result<()> {
+        let server = test_server()?;
+
+        let mut edns = Edns::new(1232);
+        edns.version = 1;
+        let response = server.handler.handle(
+            &query(1, vec![b"codecrafters", b"io"], Some(edns)),
+            Transport::Udp,
+        );
+        let response = response.unwrap();
+        let response = DNSHdr::from_bytes(&response).unwrap();
+        assert_eq!(response.id, 1);
+        assert_eq!(response.rcode(), RCode::BadVers as u16);
+        assert_eq!(response.edns.unwrap().version, EDNS_VERSION);
+        assert!(response.answers.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_name_nxdomain() -> Result<()> {
+        let server = test_server()?;
+
+        let response = server.handler.handle(
+            &query(1, vec![b"missing", b"codecrafters", b"io"], None),
+            Transport::Udp,
+        );
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        assert_eq!(response.rcode(), RCode::NameError as u16);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 1);
+        let soa = &response.authorities[0];
+        assert_eq!(soa.name, vec![b"codecrafters".as_slice(), b"io"]);
+        assert!(matches!(soa.rdata, RData::SOA { minimum: 60, .. }));
+        assert_eq!(soa.ttl, 60);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_authoritative_answers() -> Result<()> {
+        let server = test_server()?;
+
+        let response = server.handler.handle(
+            &query(1, vec![b"codecrafters", b"io"], None),
+            Transport::Udp,
+        );
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        assert_eq!(response.flags.aa, 1);
+        assert_eq!(
+            response.answers[0].rdata,
+            RData::A(Ipv4Addr::new(192, 168, 10, 10))
+        );
+
+        // names match case-insensitively, the answer keeps the asked case
+        let response = server.handler.handle(
+            &query(2, vec![b"CodeCrafters", b"IO"], None),
+            Transport::Udp,
+        );
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        assert_eq!(response.rcode(), RCode::OK as u16);
+        assert_eq!(
+            response.answers[0].name,
+            vec![b"CodeCrafters".as_slice(), b"IO"]
+        );
+
+        let response = server.handler.handle(
+            &typed_query(2, vec![b"codecrafters", b"io"], RRType::NS),
+            Transport::Udp,
+        );
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        assert_eq!(response.flags.aa, 1);
+        assert_eq!(
+            response.answers[0].rdata,
+            RData::NS(vec![b"ns1", b"codecrafters", b"io"])
+        );
+
+        // no zone encloses the name and there is nobody to forward it to
+        let response = server
+            .handler
+            .handle(&query(3, vec![b"example", b"org"], None), Transport::Udp);
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        assert_eq!(response.rcode(), RCode::Refused as u16);
+        assert_eq!(response.flags.aa, 0);
+        assert!(response.answers.is_empty());
+        assert!(response.authorities.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_zones_only_answer_class_in() -> Result<()> {
+        let server = test_server()?;
+        let ask_class = |qclass| {
+            let req = query(1, vec![b"codecrafters", b"io"], None);
+            let mut req = DNSHdr::from_bytes(&req).unwrap();
+            req.queries[0].qclass = qclass;
+            server
+                .handler
+                .handle(&req.to_bytes(), Transport::Udp)
+                .unwrap()
+        };
+
+        for qclass in [RRClass::CH as u16, RRClass::HS as u16] {
+            let response = ask_class(qclass);
+            let response = DNSHdr::from_bytes(&response)?;
+            assert_eq!(response.rcode(), RCode::Refused as u16);
+            assert!(response.answers.is_empty());
+            assert_eq!(response.flags.aa, 0);
+        }
+
+        let response = ask_class(QCLASS_ANY);
+        let response = DNSHdr::from_bytes(&response)?;
+        assert_eq!(response.rcode(), RCode::OK as u16);
+        assert_eq!(response.answers[0].qclass, RRClass::IN as u16);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_zones() -> Result<()> {
+        let server = DNSServer::new("127.0.0.1:0", None)?;
+        server.load_default_zones()?;
+
+        assert_eq!(
+            ask(&server, "codecrafters.io"),
+            (RCode::OK as u16, vec![Ipv4Addr::new(192, 168, 10, 10)])
+        );
+        assert_eq!(
+            ask(&server, "stackoverflow.com"),
+            (RCode::OK as u16, vec![Ipv4Addr::new(192, 168, 10, 20)])
+        );
+        let (rcode, _) = ask(&server, "missing.stackoverflow.com");
+        assert_eq!(rcode, RCode::NameError as u16);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wildcard_owner() -> Result<()> {
+        let server = test_server()?;
+
+        let response = server.handler.handle(
+            &query(1, vec![b"api", b"dev", b"codecrafters", b"io"], None),
+            Transport::Udp,
+        );
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        assert_eq!(response.rcode(), RCode::OK as u16);
+        assert_eq!(response.flags.aa, 1);
+        assert_eq!(response.answers.len(), 1);
+        // synthesized records are owned by the name asked for
+        assert_eq!(
+            response.answers[0].name,
+            vec![b"api".as_slice(), b"dev", b"codecrafters", b"io"]
+        );
+        assert_eq!(
+            response.answers[0].rdata,
+            RData::A(Ipv4Addr::new(192, 168, 10, 30))
+        );
+
+        Ok(())
+    }
+
+    fn cname_chain(server: &DNSServer, name: Vec<&[u8]>) -> (u16, Vec<String>) {
+        let response = server
+            .handler
+            .handle(&query(1, name, None), Transport::Udp)
+            .unwrap();
+        let response = DNSHdr::from_bytes(&response).unwrap();
+        let answers = response
+            .answers
+            .iter()
+            .map(|a| match &a.rdata {
+                RData::CNAME(target) => format!(
+                    "{} CNAME {}",
+                    DomainName::from_labels(&a.name),
+                    DomainName::from_labels(target)
+                ),
+                rdata => format!("{} {rdata:?}", DomainName::from_labels(&a.name)),
+            })
+            .collect();
+
+        (response.rcode(), answers)
+    }
+
+    #[test]
+    fn test_cname_chasing() -> Result<()> {
+        let server = test_server()?;
+
+        assert_eq!(
+            cname_chain(&server, vec![b"www", b"codecrafters", b"io"]),
+            (
+                RCode::OK as u16,
+                vec![
+                    "www.codecrafters.io. CNAME codecrafters.io.".to_string(),
+                    "codecrafters.io. A(192.168.10.10)".to_string(),
+                ]
+            )
+        );
+
+        // across zones
+        assert_eq!(
+            cname_chain(&server, vec![b"so", b"codecrafters", b"io"]),
+            (
+                RCode::OK as u16,
+                vec![
+                    "so.codecrafters.io. CNAME www.stackoverflow.com.".to_string(),
+                    "www.stackoverflow.com. CNAME stackoverflow.com.".to_string(),
+                    "stackoverflow.com. A(192.168.10.20)".to_string(),
+                ]
+            )
+        );
+
+        // targets we are not authoritative for end the chain
+        for name in [b"ext".as_slice(), b"delegated"] {
+            let (rcode, answers) = cname_chain(&server, vec![name, b"codecrafters", b"io"]);
+            assert_eq!(rcode, RCode::OK as u16);
+            assert_eq!(answers.len(), 1);
+        }
+
+        // the RCODE is the one of the last name in the chain
+        let (rcode, answers) = cname_chain(&server, vec![b"gone", b"codecrafters", b"io"]);
+        assert_eq!(rcode, RCode::NameError as u16);
+        assert_eq!(
+            answers,
+            vec!["gone.codecrafters.io. CNAME missing.codecrafters.io."]
+        );
+
+        let (rcode, answers) = cname_chain(&server, vec![b"loop1", b"codecrafters", b"io"]);
+        assert_eq!(rcode, RCode::ServerFailure as u16);
+        assert_eq!(answers.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cname_chain_limit() -> Result<()> {
+        let server = DNSServer::new("127.0.0.1:0", None)?;
+        let mut src = "$TTL 60\n@ SOA ns h 1 1 1 1 1\nend A 192.0.2.1\n".to_string();
+        for i in 0..MAX_CNAME_CHAIN + 1 {
+            src += &format!("a{i} CNAME a{}\n", i + 1);
+        }
+        src += &format!("a{} CNAME end\n", MAX_CNAME_CHAIN + 1);
+        let origin: DomainName = "example.com".parse()?;
+        let zone = Zone::new(origin.clone(), zone::parse(&src, "t.zone", &origin)?)?;
+        server.handler.zones.write().unwrap().add(zone);
+
+        let (rcode, answers) = cname_chain(&server, vec![b"a0", b"example", b"com"]);
+        assert_eq!(rcode, RCode::ServerFailure as u16);
+        assert_eq!(answers.len(), MAX_CNAME_CHAIN + 1);
+
+        // two links fewer is within the limit
+        let (rcode, answers) = cname_chain(&server, vec![b"a2", b"example", b"com"]);
+        assert_eq!(rcode, RCode::OK as u16);
+        assert_eq!(answers.len(), MAX_CNAME_CHAIN + 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_referral() -> Result<()> {
+        let server = test_server()?;
+
+        let response = server.handler.handle(
+            &query(1, vec![b"www", b"sub", b"codecrafters", b"io"], None),
+            Transport::Udp,
+        );
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        assert_eq!(response.rcode(), RCode::OK as u16);
+        assert_eq!(response.flags.aa, 0);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(
+            response.authorities[0].name,
+            vec![b"sub".as_slice(), b"codecrafters", b"io"]
+        );
+        assert_eq!(
+            response.authorities[0].rdata,
+            RData::NS(vec![b"ns", b"sub", b"codecrafters", b"io"])
+        );
+        assert_eq!(response.additionals.len(), 1);
+        assert_eq!(
+            response.additionals[0].rdata,
+            RData::A(Ipv4Addr::new(192, 168, 10, 53))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_type_nodata() -> Result<()> {
+        let server = test_server()?;
+
+        let response = server.handler.handle(
+            &typed_query(1, vec![b"stackoverflow", b"com"], RRType::MX),
+            Transport::Udp,
+        );
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        assert_eq!(response.rcode(), RCode::OK as u16);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(response.authorities[0].qtype, RRType::SOA as u16);
+
+        let response = server.handler.handle(
+            &query(2, vec![b"stackoverflow", b"com"], None),
+            Transport::Udp,
+        );
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        assert_eq!(response.rcode(), RCode::OK as u16);
+        assert_eq!(
+            response.answers[0].rdata,
+            RData::A(Ipv4Addr::new(192, 168, 10, 20))
+        );
+        assert!(response.authorities.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_payload_limit() {
+        let flags = Flags {
+            qr: 0,
+            opcode: 0,
+            aa: 0,
+            tc: 0,
+            rd: 0,
+            ra: 0,
+            rcode: 0,
+        };
+        let mut request = DNSHdr::new(1, flags, vec![], vec![]);
+        assert_eq!(payload_limit(&request), 512);
+
+        request.edns = Some(Edns::new(1232));
+        assert_eq!(payload_limit(&request), 1232);
+
+        request.edns = Some(Edns::new(100));
+        assert_eq!(payload_limit(&request), 512);
+
+        request.edns = Some(Edns::new(65000));
+        assert_eq!(payload_limit(&request), EDNS_UDP_SIZE as usize);
+    }
+
+    #[test]
+    fn test_truncated_response_fits_limit() -> Result<()> {
+        let server = test_server()?;
+
+        // 40 questions are answered with 40 answers, too many for 512 bytes
+        let question = Query {
+            name: vec![b"codecrafters", b"io"],
+            qtype: RRType::A as u16,
+            qclass: RRClass::IN as u16,
+        };
+        let mut request = DNSHdr::new(
+            1,
+            Flags {
+                qr: 0,
+                opcode: 0,
+                aa: 0,
+                tc: 0,
+                rd: 0,
+                ra: 0,
+                rcode: 0,
+            },
+            vec![question; 40],
+            vec![],
+        );
+        let bytes = request.to_bytes();
+        assert!(bytes.len() <= 512);
+
+        let response = server.handler.handle(&bytes, Transport::Udp).unwrap();
+        assert!(response.len() <= MAX_UDP_SIZE);
+        let response = DNSHdr::from_bytes(&response).unwrap();
+        assert_eq!(response.flags.tc, 1);
+
+        request.edns = Some(Edns::new(4096));
+        let response = server
+            .handler
+            .handle(&request.to_bytes(), Transport::Udp)
+            .unwrap();
+        let response = DNSHdr::from_bytes(&response).unwrap();
+        assert_eq!(response.flags.tc, 0);
+        assert_eq!(response.answers.len(), 40);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_workers_bounded() -> Result<()> {
+        let workers = Workers::new(2);
+        let (release, wait) = mpsc::channel::<()>();
+        let wait = Arc::new(Mutex::new(wait));
+        let (done, finished) = mpsc::channel();
+
+        for _ in 0..2 {
+            let (wait, done) = (wait.clone(), done.clone());
+            workers.spawn(move || {
+                let _ = wait.lock().unwrap().recv();
+                done.send(()).unwrap();
+            })?;
+        }
+        assert!(workers.spawn(|| {}).is_err());
+
+        // a slot comes free once a worker is done
+        release.send(())?;
+        finished.recv()?;
+        thread::sleep(Duration::from_millis(20));
+        assert!(workers.spawn(|| {}).is_ok());
+
+        drop(release);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tcp_connections_bounded() -> Result<()> {
+        let mut server = test_server()?;
+        server.max_tcp_connections = 1;
+        let addr = server.local_addr()?;
+        thread::spawn(move || server.start());
+
+        let mut first = TcpStream::connect(addr)?;
+        write_message(&mut first, &query(1, vec![b"codecrafters", b"io"], None))?;
+        assert!(!read_message(&mut first)?.is_empty());
+
+        // the second connection is closed while the first is open
+        let mut second = TcpStream::connect(addr)?;
+        second.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let mut buf = [0; 1];
+        assert_eq!(second.read(&mut buf)?, 0);
+
+        Ok(())
+    }
+
+    fn start_server() -> Result<(SocketAddr, Duration)> {
+        let mut server = test_server()?;
+        server.tcp_idle_timeout = Duration::from_millis(200);
+        let addr = server.socket.local_addr()?;
+        let idle_timeout = server.tcp_idle_timeout;
+        thread::spawn(move || server.start());
+
+        Ok((addr, idle_timeout))
+    }
+
+    #[test]
+    fn test_tcp_pipelined_queries() -> Result<()> {
+        let (addr, _) = start_server()?;
+        let mut stream = TcpStream::connect(addr)?;
+
+        // both queries go out before reading any response
+        let mut buf = BytesMut::new();
+        for id in [1, 2] {
+            let req = query(id, vec![b"codecrafters", b"io"], None);
+            buf.put_u16(req.len() as u16);
+            buf.extend_from_slice(&req);
+        }
+        stream.write_all(&buf)?;
+
+        for id in [1, 2] {
+            let response = read_message(&mut stream)?;
+            let response = DNSHdr::from_bytes(&response).unwrap();
+            assert_eq!(response.id, id);
+            assert_eq!(response.answers.len(), 1);
+        }
+
+        // the connection stays usable for another query
+        let req = query(3, vec![b"codecrafters", b"io"], None);
+        write_message(&mut stream, &req)?;
+        let response = read_message(&mut stream)?;
+        assert_eq!(DNSHdr::from_bytes(&response).unwrap().id, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tcp_not_truncated() -> Result<()> {
+        let (addr, _) = start_server()?;
+        let mut stream = TcpStream::connect(addr)?;
+
+        let question = Query {
+            name: vec![b"codecrafters", b"io"],
+            qtype: RRType::A as u16,
+            qclass: RRClass::IN as u16,
+        };
+        let flags = Flags {
+            qr: 0,
+            opcode: 0,
+            aa: 0,
+            tc: 0,
+            rd: 0,
+            ra: 0,
+            rcode: 0,
+        };
+        let req = DNSHdr::new(1, flags, vec![question; 40], vec![]).to_bytes();
+        write_message(&mut stream, &req)?;
+
+        let response = read_message(&mut stream)?;
+        assert!(response.len() > MAX_UDP_SIZE);
+        let response = DNSHdr::from_bytes(&response).unwrap();
+        assert_eq!(response.flags.tc, 0);
+        assert_eq!(response.answers.len(), 40);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tcp_idle_timeout() -> Result<()> {
+        let (addr, idle_timeout) = start_server()?;
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(idle_timeout * 10))?;
+
+        thread::sleep(idle_timeout * 2);
+        let mut buf = [0; 1];
+        assert_eq!(stream.read(&mut buf)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_servfail_when_upstream_silent() -> Result<()> {
+        let server = DNSServer::new("127.0.0.1:0", Some(silent_upstream()?))?;
+
+        let response = server
+            .handler
+            .handle(&query(9, vec![b"example", b"com"], None), Transport::Udp);
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        assert_eq!(response.id, 9);
+        assert_eq!(response.rcode(), RCode::ServerFailure as u16);
+        assert!(response.answers.is_empty());
+
+        Ok(())
+    }
+
+    // An upstream that answers NXDOMAIN under "missing" and otherwise
+    // returns an MX record with its glue in the additional section
+    fn relaying_upstream() -> Result<SocketAddr> {
+        mock_upstream(|request, _| {
+            let mut response = response(request, vec![]);
+            let q = &request.queries[0];
+            if q.name[0] == b"missing" {
+                response.set_rcode(RCode::NameError as u16);
+                response.authorities.push(Answer::new(
+                    vec![b"example", b"com"],
+                    RRClass::IN,
+                    300,
+                    RData::SOA {
+                        mname: vec![b"ns", b"example", b"com"],
+                        rname: vec![b"hostmaster", b"example", b"com"],
+                        serial: 1,
+                        refresh: 3600,
+                        retry: 600,
+                        expire: 86400,
+                        minimum: 60,
+                    },
+                ));
+            } else {
+                response.answers.push(Answer::new(
+                    q.name.clone(),
+                    RRClass::IN,
+                    300,
+                    RData::MX {
+                        preference: 10,
+                        exchange: vec![b"mail", b"example", b"com"],
+                    },
+                ));
+                response.additionals.push(Answer::new(
+                    vec![b"mail", b"example", b"com"],
+                    RRClass::IN,
+                    300,
+                    RData::A(Ipv4Addr::new(192, 0, 2, 25)),
+                ));
+            }
+            vec![response.to_bytes().to_vec()]
+        })
+    }
+
+    fn typed_query(id: u16, name: Vec<&[u8]>, qtype: RRType) -> Bytes {
+        DNSHdr::new(
+            id,
+            Flags {
+                qr: 0,
+                opcode: OpCode::QUERY as u8,
+                aa: 0,
+                tc: 0,
+                rd: 1,
+                ra: 0,
+                rcode: 0,
+            },
+            vec![Query {
+                name,
+                qtype: qtype as u16,
+                qclass: RRClass::IN as u16,
+            }],
+            vec![],
+        )
+        .to_bytes()
+    }
+
+    // An upstream that answers ANY with every type it has for the name, and
+    // with no records at all for names under "empty"
+    fn any_upstream() -> Result<SocketAddr> {
+        mock_upstream(|request, _| {
+            let q = &request.queries[0];
+            let mut response = response(request, vec![]);
+            if q.name[0] == b"empty" {
+                response.authorities.push(Answer::new(
+                    vec![b"example", b"com"],
+                    RRClass::IN,
+                    300,
+                    RData::SOA {
+                        mname: vec![b"ns", b"example", b"com"],
+                        rname: vec![b"hostmaster", b"example", b"com"],
+                        serial: 1,
+                        refresh: 3600,
+                        retry: 600,
+                        expire: 86400,
+                        minimum: 60,
+                    },
+                ));
+            } else {
+                response.answers = vec![
+                    Answer::new(
+                        q.name.clone(),
+                        RRClass::IN,
+                        300,
+                        RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                    ),
+                    Answer::new(
+                        q.name.clone(),
+                        RRClass::IN,
+                        300,
+                        RData::MX {
+                            preference: 10,
+                            exchange: vec![b"mail", b"example", b"com"],
+                        },
+                    ),
+                ];
+            }
+            vec![response.to_bytes().to_vec()]
+        })
+    }
+
+    #[test]
+    fn test_forwards_qtype_any() -> Result<()> {
+        let upstream = any_upstream()?;
+        let resolver = Resolver::new(&[upstream], ResolverOptions::default())?;
+        let server = DNSServer::new("127.0.0.1:0", Some(Upstream::Forward(resolver)))?;
+        let any = |id, name| {
+            let req = typed_query(id, name, RRType::A);
+            let mut req = DNSHdr::from_bytes(&req).unwrap();
+            req.queries[0].qtype = QTYPE_ANY;
+            server
+                .handler
+                .handle(&req.to_bytes(), Transport::Udp)
+                .unwrap()
+        };
+
+        let response = any(1, vec![b"example", b"com"]);
+        let response = DNSHdr::from_bytes(&response)?;
+        assert_eq!(response.rcode(), RCode::OK as u16);
+        assert_eq!(response.queries[0].qtype, QTYPE_ANY);
+        let types = response.answers.iter().map(|a| a.qtype).collect::<Vec<_>>();
+        assert_eq!(types, vec![RRType::A as u16, RRType::MX as u16]);
+
+        let response = any(2, vec![b"empty", b"example", b"com"]);
+        assert!(DNSHdr::from_bytes(&response)?.answers.is_empty());
+
+        // an empty answer to ANY is not kept as NODATA
+        server.handler.upstreams.write().unwrap().default = Some(silent_upstream()?);
+        let response = any(3, vec![b"empty", b"example", b"com"]);
+        assert_eq!(
+            DNSHdr::from_bytes(&response)?.rcode(),
+            RCode::ServerFailure as u16
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_forwards_any_type() -> Result<()> {
+        let upstream = relaying_upstream()?;
+        let resolver = Resolver::new(&[upstream], ResolverOptions::default())?;
+        let server = DNSServer::new("127.0.0.1:0", Some(Upstream::Forward(resolver)))?;
+
+        let response = server.handler.handle(
+            &typed_query(3, vec![b"example", b"com"], RRType::MX),
+            Transport::Udp,
+        );
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        assert_eq!(response.id, 3);
+        assert_eq!(response.rcode(), RCode::OK as u16);
+        assert_eq!(response.queries[0].qtype, RRType::MX as u16);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].qtype, RRType::MX as u16);
+        assert_eq!(response.additionals.len(), 1);
+        assert_eq!(
+            response.additionals[0].rdata,
+            RData::A(Ipv4Addr::new(192, 0, 2, 25))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_forwards_nxdomain() -> Result<()> {
+        let upstream = relaying_upstream()?;
+        let resolver = Resolver::new(&[upstream], ResolverOptions::default())?;
+        let server = DNSServer::new("127.0.0.1:0", Some(Upstream::Forward(resolver)))?;
+
+        let response = server.handler.handle(
+            &typed_query(4, vec![b"missing", b"example", b"com"], RRType::MX),
+            Transport::Udp,
+        );
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        assert_eq!(response.id, 4);
+        assert_eq!(response.rcode(), RCode::NameError as u16);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(response.authorities[0].qtype, RRType::SOA as u16);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_answers_from_cache() -> Result<()> {
+        let upstream = relaying_upstream()?;
+        let resolver = Resolver::new(&[upstream], ResolverOptions::default())?;
+        let server = DNSServer::new("127.0.0.1:0", Some(Upstream::Forward(resolver)))?;
+        let mx = || typed_query(5, vec![b"example", b"com"], RRType::MX);
+
+        let first = server.handler.handle(&mx(), Transport::Udp);
+        let first = DNSHdr::from_bytes(first.as_ref().unwrap()).unwrap();
+        assert_eq!(first.answers.len(), 1);
+
+        // the upstream goes away, the second query never leaves the server
+        server.handler.upstreams.write().unwrap().default = Some(silent_upstream()?);
+
+        let second = server.handler.handle(&mx(), Transport::Udp);
+        let second = DNSHdr::from_bytes(second.as_ref().unwrap()).unwrap();
+        assert_eq!(second.rcode(), RCode::OK as u16);
+        assert_eq!(second.flags.aa, 0);
+        assert_eq!(second.answers.len(), 1);
+        assert_eq!(second.answers[0].rdata, first.answers[0].rdata);
+        assert!(second.answers[0].ttl <= first.answers[0].ttl);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_answers_nxdomain_from_cache() -> Result<()> {
+        let upstream = relaying_upstream()?;
+        let resolver = Resolver::new(&[upstream], ResolverOptions::default())?;
+        let server = DNSServer::new("127.0.0.1:0", Some(Upstream::Forward(resolver)))?;
+        let missing = vec![b"missing".as_slice(), b"example", b"com"];
+
+        let first = server
+            .handler
+            .handle(&typed_query(6, missing.clone(), RRType::MX), Transport::Udp);
+        let first = DNSHdr::from_bytes(first.as_ref().unwrap()).unwrap();
+        assert_eq!(first.rcode(), RCode::NameError as u16);
+
+        server.handler.upstreams.write().unwrap().default = Some(silent_upstream()?);
+
+        // the name does not exist whatever type is asked for
+        let second = server
+            .handler
+            .handle(&typed_query(7, missing, RRType::A), Transport::Udp);
+        let second = DNSHdr::from_bytes(second.as_ref().unwrap()).unwrap();
+        assert_eq!(second.rcode(), RCode::NameError as u16);
+        assert!(second.answers.is_empty());
+        assert_eq!(second.authorities.len(), 1);
+        assert_eq!(
+            second.authorities[0].name,
+            vec![b"example".as_slice(), b"com"]
+        );
+        // the SOA TTL is capped to its minimum of 60
+        assert!(second.authorities[0].ttl <= 60);
+        assert!(matches!(
+            second.authorities[0].rdata,
+            RData::SOA { minimum: 60, .. }
+        ));
+
+        Ok(())
+    }
+
+    // An upstream that answers with an alias to www.example.com, slipping in
+    // records for names nobody asked about
+    fn poisoning_upstream() -> Result<SocketAddr> {
+        mock_upstream(|request, _| {
+            let www = vec![b"www".as_slice(), b"example", b"com"];
+            let spoofed =
+                |name| Answer::new(name, RRClass::IN, 300, RData::A(Ipv4Addr::new(6, 6, 6, 6)));
+            let answers = vec![
+                spoofed(vec![b"victim", b"example", b"com"]),
+                Answer::new(
+                    request.queries[0].name.clone(),
+                    RRClass::IN,
+                    300,
+                    RData::CNAME(www.clone()),
+                ),
+                spoofed(vec![b"bank", b"com"]),
+                Answer::new(www, RRClass::IN, 300, RData::A(Ipv4Addr::new(10, 0, 0, 1))),
+            ];
+            vec![response(request, answers).to_bytes().to_vec()]
+        })
+    }
+
+    #[test]
+    fn test_caches_only_the_cname_chain() -> Result<()> {
+        let upstream = poisoning_upstream()?;
+        let resolver = Resolver::new(&[upstream], ResolverOptions::default())?;
+        let server = DNSServer::new("127.0.0.1:0", Some(Upstream::Forward(resolver)))?;
+
+        let first = server.handler.handle(
+            &typed_query(1, vec![b"alias", b"example", b"com"], RRType::A),
+            Transport::Udp,
+        );
+        let first = DNSHdr::from_bytes(first.as_ref().unwrap()).unwrap();
+        assert_eq!(first.answers.len(), 2);
+        assert_eq!(first.answers[0].qtype, RRType::CNAME as u16);
+        assert_eq!(first.answers[1].rdata, RData::A(Ipv4Addr::new(10, 0, 0, 1)));
+
+        server.handler.upstreams.write().unwrap().default = Some(silent_upstream()?);
+
+        // the target of the alias was cached
+        let www = server.handler.handle(
+            &typed_query(2, vec![b"www", b"example", b"com"], RRType::A),
+            Transport::Udp,
+        );
+        let www = DNSHdr::from_bytes(www.as_ref().unwrap()).unwrap();
+        assert_eq!(www.rcode(), RCode::OK as u16);
+        assert_eq!(www.answers[0].rdata, RData::A(Ipv4Addr::new(10, 0, 0, 1)));
+
+        // the names nobody asked about were not
+        for (id, name) in [
+            (3, vec![b"victim".as_slice(), b"example", b"com"]),
+            (4, vec![b"bank", b"com"]),
+        ] {
+            let spoofed = server
+                .handler
+                .handle(&typed_query(id, name, RRType::A), Transport::Udp);
+            let spoofed = DNSHdr::from_bytes(spoofed.as_ref().unwrap()).unwrap();
+            assert_eq!(spoofed.rcode(), RCode::ServerFailure as u16);
+            assert!(spoofed.answers.is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_follows_cached_cnames() -> Result<()> {
+        let upstream = poisoning_upstream()?;
+        let resolver = Resolver::new(&[upstream], ResolverOptions::default())?;
+        let server = DNSServer::new("127.0.0.1:0", Some(Upstream::Forward(resolver)))?;
+        let alias = || typed_query(1, vec![b"Alias", b"example", b"com"], RRType::A);
+
+        server.handler.handle(&alias(), Transport::Udp);
+
+        server.handler.upstreams.write().unwrap().default = Some(silent_upstream()?);
+
+        // there is no A record at the alias, only the CNAME to follow
+        let response = server.handler.handle(&alias(), Transport::Udp);
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        assert_eq!(response.rcode(), RCode::OK as u16);
+        assert_eq!(response.answers.len(), 2);
+        assert_eq!(
+            response.answers[0].name,
+            vec![b"Alias".as_slice(), b"example", b"com"]
+        );
+        assert_eq!(
+            response.answers[0].rdata,
+            RData::CNAME(vec![b"www", b"example", b"com"])
+        );
+        assert_eq!(
+            response.answers[1].name,
+            vec![b"www".as_slice(), b"example", b"com"]
+        );
+        assert_eq!(
+            response.answers[1].rdata,
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+
+        // the chain is only followed as far as it is cached
+        let response = server.handler.handle(
+            &typed_query(2, vec![b"alias", b"example", b"com"], RRType::MX),
+            Transport::Udp,
+        );
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        assert_eq!(response.rcode(), RCode::ServerFailure as u16);
+
+        Ok(())
+    }
+
+    // An upstream that answers every A question with ip
+    fn fixed_upstream(ip: Ipv4Addr) -> Result<SocketAddr> {
+        mock_upstream(move |request, _| {
+            let answers = vec![Answer::new(
+                request.queries[0].name.clone(),
+                RRClass::IN,
+                60,
+                RData::A(ip),
+            )];
+            vec![response(request, answers).to_bytes().to_vec()]
+        })
+    }
+
+    fn resolver_for(last: u8) -> Result<Resolver> {
+        let upstream = fixed_upstream(Ipv4Addr::new(10, 0, 0, last))?;
+        Resolver::new(&[upstream], ResolverOptions::default())
+    }
+
+    // The rcode and the A records in the response
+    fn ask(server: &DNSServer, name: &str) -> (u16, Vec<Ipv4Addr>) {
+        let response = server
+            .handler
+            .handle(&query(1, self::name(name).labels(), None), Transport::Udp);
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap()).unwrap();
+        let ips = response
+            .answers
+            .iter()
+            .filter_map(|a| match a.rdata {
+                RData::A(ip) => Some(ip),
+                _ => None,
+            })
+            .collect();
+        (response.rcode(), ips)
+    }
+
+    #[test]
+    fn test_conditional_forwarding() -> Result<()> {
+        let server = test_server()?;
+        server.handler.upstreams.write().unwrap().default =
+            Some(Upstream::Forward(resolver_for(1)?));
+        server.add_forwarder(name("corp.internal"), resolver_for(2)?);
+        server.add_forwarder(name("k8s.local"), resolver_for(3)?);
+        server.add_forwarder(name("dev.corp.internal"), resolver_for(4)?);
+        let ok = |last| (RCode::OK as u16, vec![Ipv4Addr::new(10, 0, 0, last)]);
+
+        assert_eq!(ask(&server, "www.example.com"), ok(1));
+        assert_eq!(ask(&server, "corp.internal"), ok(2));
+        assert_eq!(ask(&server, "mail.CORP.internal"), ok(2));
+        assert_eq!(ask(&server, "notcorp.internal"), ok(1));
+        assert_eq!(ask(&server, "svc.k8s.local"), ok(3));
+        // the longest domain wins
+        assert_eq!(ask(&server, "x.dev.corp.internal"), ok(4));
+
+        // our own zones win over forwarders
+        server.add_forwarder(name("codecrafters.io"), resolver_for(5)?);
+        let (rcode, ips) = ask(&server, "codecrafters.io");
+        assert_eq!(rcode, RCode::OK as u16);
+        assert_eq!(ips, vec![Ipv4Addr::new(192, 168, 10, 10)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keeps_answers_when_one_forward_fails() -> Result<()> {
+        let server = test_server()?;
+        server.add_forwarder(name("corp.internal"), resolver_for(2)?);
+        server.add_forwarder(name("down.internal"), silent_resolver()?);
+
+        let req = query(1, vec![b"www", b"corp", b"internal"], None);
+        let mut req = DNSHdr::from_bytes(&req)?;
+        req.queries.push(Query {
+            name: vec![b"www", b"down", b"internal"],
+            qtype: RRType::A as u16,
+            qclass: RRClass::IN as u16,
+        });
+        let response = server.handler.handle(&req.to_bytes(), Transport::Udp);
+        let response = DNSHdr::from_bytes(response.as_ref().unwrap())?;
+
+        assert_eq!(response.rcode(), RCode::ServerFailure as u16);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(
+            response.answers[0].rdata,
+            RData::A(Ipv4Addr::new(10, 0, 0, 2))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_forwarders_without_default() -> Result<()> {
+        let server = test_server()?;
+        server.add_forwarder(name("corp.internal"), resolver_for(2)?);
+
+        assert_eq!(
+            ask(&server, "www.corp.internal"),
+            (RCode::OK as u16, vec![Ipv4Addr::new(10, 0, 0, 2)])
+        );
+        assert_eq!(
+            ask(&server, "www.example.com"),
+            (RCode::Refused as u16, vec![])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recursion_available() -> Result<()> {
+        let server = test_server()?;
+        server.add_forwarder(name("corp.internal"), resolver_for(2)?);
+        let ra = |name: Vec<&[u8]>| {
+            let response = server.handler.handle(&query(1, name, None), Transport::Udp);
+            DNSHdr::from_bytes(response.as_ref().unwrap())
+                .unwrap()
+                .flags
+                .ra
+        };
+
+        assert_eq!(ra(vec![b"www", b"corp", b"internal"]), 1);
+        assert_eq!(ra(vec![b"codecrafters", b"io"]), 0);
+        assert_eq!(ra(vec![b"www", b"example", b"com"]), 0);
+
+        server.handler.upstreams.write().unwrap().default =
+            Some(Upstream::Forward(resolver_for(1)?));
+        assert_eq!(ra(vec![b"www", b"example", b"com"]), 1);
+        // even for our own zones, which are not forwarded
+        assert_eq!(ra(vec![b"codecrafters", b"io"]), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_answers_while_forwarding() -> Result<()> {
+        let slow = mock_upstream(|request, _| {
+            thread::sleep(Duration::from_millis(500));
+            vec![response(request, vec![]).to_bytes().to_vec()]
+        })?;
+        let server = test_server()?;
+        server.add_forwarder(
+            name("slow.example"),
+            Resolver::new(&[slow], ResolverOptions::default())?,
+        );
+
+        let handler = server.handler.clone();
+        let forwarding = thread::spawn(move || {
+            handler.handle(&query(1, vec![b"slow", b"example"], None), Transport::Udp)
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        // our zones and the cache are not held up by the upstream
+        let start = Instant::now();
+        let (rcode, ips) = ask(&server, "codecrafters.io");
+        assert_eq!(rcode, RCode::OK as u16);
+        assert_eq!(ips, vec![Ipv4Addr::new(192, 168, 10, 10)]);
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        assert!(forwarding.join().unwrap().is_some());
+
+        Ok(())
+    }
 }
@@ -1,17 +1,142 @@
-use anyhow::Result;
-use dns_server::DNSServer;
+use anyhow::{Context, Result};
+use dns_server::{DNSServer, Upstream};
+use name::DomainName;
+use recursor::{Minimisation, Recursor, RecursorOptions};
+use resolver::{Policy, Resolver, ResolverOptions};
 use std::env;
+use std::path::Path;
+use std::time::Duration;
 
+mod authority;
+mod cache;
 mod dns_hdr;
 mod dns_server;
+mod name;
+mod recursor;
+mod resolver;
+mod tcp;
+#[cfg(test)]
+mod testing;
+mod zone;
 
-fn main() -> Result<()> {
-    let resolver = env::args()
+fn args(name: &str) -> Vec<String> {
+    env::args()
         .zip(env::args().skip(1))
-        .find(|(k, _v)| k == "--resolver")
-        .map(|(_, v)| v);
+        .filter(|(k, _v)| k == name)
+        .map(|(_, v)| v)
+        .collect()
+}
+
+fn arg(name: &str) -> Option<String> {
+    args(name).into_iter().next()
+}
+
+fn policy(name: &str) -> Result<Policy> {
+    Ok(match name {
+        "failover" => Policy::Failover,
+        "round-robin" => Policy::RoundRobin,
+        "random" => Policy::Random,
+        "fastest" => Policy::Fastest,
+        _ => anyhow::bail!("{name:?}, expected failover, round-robin, random or fastest"),
+    })
+}
+
+/*
+A conditional forwarder: <domain>=<upstream>[,<upstream>...] followed by any
+of policy=<policy>, timeout-ms=<ms> and retries=<n>, which override the
+global resolver options for this domain only.
+*/
+fn forwarder(spec: &str, defaults: ResolverOptions) -> Result<(DomainName, Resolver)> {
+    let (domain, entry) = spec
+        .split_once('=')
+        .context("expected <domain>=<upstream>[,<upstream>...]")?;
+    let domain = domain
+        .parse()
+        .with_context(|| format!("invalid domain {domain:?}"))?;
+
+    let mut options = defaults;
+    let mut upstreams = vec![];
+    for field in entry.split(',') {
+        match field.split_once('=') {
+            Some(("policy", name)) => options.policy = policy(name)?,
+            Some(("timeout-ms", ms)) => {
+                options.timeout = Duration::from_millis(ms.parse().context("invalid timeout-ms")?)
+            }
+            Some(("retries", retries)) => {
+                options.retries = retries.parse().context("invalid retries")?
+            }
+            Some((option, _)) => anyhow::bail!("unknown option {option:?}"),
+            None => upstreams.push(field),
+        }
+    }
+
+    Ok((domain, Resolver::new(&upstreams, options)?))
+}
+
+fn main() -> Result<()> {
+    let mut options = ResolverOptions::default();
+    if let Some(ms) = arg("--timeout-ms") {
+        options.timeout = Duration::from_millis(ms.parse().context("invalid --timeout-ms")?);
+    }
+    if let Some(retries) = arg("--retries") {
+        options.retries = retries.parse().context("invalid --retries")?;
+    }
+    if let Some(name) = arg("--resolver-policy") {
+        options.policy = policy(&name).context("invalid --resolver-policy")?;
+    }
+
+    let mut recursion = RecursorOptions::default();
+    if let Some(mode) = arg("--qname-minimisation") {
+        recursion.minimise = match mode.as_str() {
+            "off" => Minimisation::Off,
+            "strict" => Minimisation::Strict,
+            "relaxed" => Minimisation::Relaxed,
+            _ => anyhow::bail!(
+                "invalid --qname-minimisation {mode:?}, expected off, strict or relaxed"
+            ),
+        };
+    }
+
+    // --resolver forwards to upstreams, once per upstream, --root-hints
+    // resolves iteratively starting from the root servers in a hints file
+    let upstreams = args("--resolver");
+    let resolver = match (upstreams.is_empty(), arg("--root-hints")) {
+        (false, Some(_)) => anyhow::bail!("--resolver and --root-hints exclude each other"),
+        (false, None) => Some(Upstream::Forward(
+            Resolver::new(&upstreams, options)
+                .with_context(|| format!("invalid --resolver {upstreams:?}"))?,
+        )),
+        (true, Some(path)) => {
+            let hints = zone::load(&path, &DomainName::root())?;
+            Some(Upstream::Recursive(
+                Recursor::new(&hints, options, recursion)
+                    .with_context(|| format!("invalid root hints {path:?}"))?,
+            ))
+        }
+        (true, None) => None,
+    };
 
-    let mut server = DNSServer::new("127.0.0.1:2053", resolver)?;
+    let server = DNSServer::new("127.0.0.1:2053", resolver)?;
+    // --zone <origin>=<master file>, once per zone, or the built-in zones
+    let zones = args("--zone");
+    if zones.is_empty() {
+        server.load_default_zones()?;
+    }
+    for zone in zones {
+        let (origin, path) = zone
+            .split_once('=')
+            .with_context(|| format!("invalid --zone {zone:?}, expected <origin>=<file>"))?;
+        let origin = origin
+            .parse()
+            .with_context(|| format!("invalid zone origin {origin:?}"))?;
+        server.load_zone(Path::new(path), origin)?;
+    }
+    // --forward <domain>=<upstreams and options>, once per domain
+    for spec in args("--forward") {
+        let (domain, resolver) =
+            forwarder(&spec, options).with_context(|| format!("invalid --forward {spec:?}"))?;
+        server.add_forwarder(domain, resolver);
+    }
     server.start();
 
     Ok(())
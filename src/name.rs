@@ -0,0 +1,345 @@
+use crate::dns_hdr::{MAX_LABEL_LEN, MAX_NAME_LEN};
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum NameParseError {
+    #[error("empty label in {0:?}")]
+    EmptyLabel(String),
+    #[error("label longer than {MAX_LABEL_LEN} bytes in {0:?}")]
+    LabelTooLong(String),
+    #[error("{0:?} is longer than {MAX_NAME_LEN} bytes")]
+    NameTooLong(String),
+    #[error("invalid escape in {0:?}")]
+    BadEscape(String),
+}
+
+// Decodes the escape following a backslash: \X stands for X and \DDD for the
+// byte with that decimal value. Returns the byte and how many bytes it took.
+pub fn decode_escape(escape: &[u8]) -> Option<(u8, usize)> {
+    match escape {
+        [a, b, c, ..] if [a, b, c].iter().all(|d| d.is_ascii_digit()) => {
+            let value = [a, b, c]
+                .iter()
+                .fold(0u16, |v, d| v * 10 + (*d - b'0') as u16);
+            Some((u8::try_from(value).ok()?, 3))
+        }
+        [b, ..] => Some((*b, 1)),
+        [] => None,
+    }
+}
+
+/*
+An owned domain name, kept as its raw labels so that dots and any other byte
+inside a label survive. Names compare, hash and order case-insensitively
+(RFC 4343), ordering is the canonical one of RFC 4034 6.1: label by label
+starting from the root. The root name has no labels.
+*/
+#[derive(Clone, Default)]
+pub struct DomainName {
+    labels: Vec<Vec<u8>>,
+}
+
+impl DomainName {
+    pub fn root() -> Self {
+        Self::default()
+    }
+
+    pub fn from_labels(labels: &[&[u8]]) -> Self {
+        DomainName {
+            labels: labels.iter().map(|l| l.to_vec()).collect(),
+        }
+    }
+
+    // The labels in the form the wire codec uses
+    pub fn labels(&self) -> Vec<&[u8]> {
+        self.labels.iter().map(Vec::as_slice).collect()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    // The name one label up, the root has no parent
+    pub fn parent(&self) -> Option<DomainName> {
+        (!self.is_root()).then(|| DomainName {
+            labels: self.labels[1..].to_vec(),
+        })
+    }
+
+    // The name itself followed by its ancestors, up to and including the root
+    pub fn ancestors(&self) -> impl Iterator<Item = DomainName> {
+        std::iter::successors(Some(self.clone()), DomainName::parent)
+    }
+
+    pub fn child(&self, label: &[u8]) -> DomainName {
+        let mut labels = vec![label.to_vec()];
+        labels.extend(self.labels.iter().cloned());
+        DomainName { labels }
+    }
+
+    // Whether self is at or below other
+    pub fn is_subdomain_of(&self, other: &DomainName) -> bool {
+        self.labels.len() >= other.labels.len()
+            && self
+                .labels
+                .iter()
+                .rev()
+                .zip(other.labels.iter().rev())
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+
+    /*
+    Parses a name in presentation format. A name ending in an unescaped dot is
+    absolute, anything else is relative to origin. Inside a label \. stands for
+    a dot and \DDD for any byte.
+    */
+    pub fn parse_relative(text: &str, origin: &DomainName) -> Result<Self, NameParseError> {
+        if text == "." {
+            return Ok(DomainName::root());
+        }
+
+        let bytes = text.as_bytes();
+        let mut labels = vec![];
+        let mut label = vec![];
+        let mut absolute = false;
+        let mut i = 0;
+
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\\' => {
+                    let (b, len) = decode_escape(&bytes[i + 1..])
+                        .ok_or_else(|| NameParseError::BadEscape(text.to_string()))?;
+                    label.push(b);
+                    i += len;
+                }
+                b'.' if label.is_empty() => {
+                    return Err(NameParseError::EmptyLabel(text.to_string()))
+                }
+                b'.' => {
+                    labels.push(std::mem::take(&mut label));
+                    absolute = i == bytes.len() - 1;
+                }
+                b => label.push(b),
+            }
+            i += 1;
+        }
+
+        if !label.is_empty() {
+            labels.push(label);
+        } else if !absolute {
+            return Err(NameParseError::EmptyLabel(text.to_string()));
+        }
+        if !absolute {
+            labels.extend(origin.labels.iter().cloned());
+        }
+
+        if labels.iter().any(|l| l.len() > MAX_LABEL_LEN) {
+            return Err(NameParseError::LabelTooLong(text.to_string()));
+        }
+        if labels.iter().map(|l| l.len() + 1).sum::<usize>() + 1 > MAX_NAME_LEN {
+            return Err(NameParseError::NameTooLong(text.to_string()));
+        }
+
+        Ok(DomainName { labels })
+    }
+}
+
+impl FromStr for DomainName {
+    type Err = NameParseError;
+
+    // Names are taken to be absolute, with or without the final dot
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DomainName::parse_relative(s, &DomainName::root())
+    }
+}
+
+impl fmt::Display for DomainName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return f.write_str(".");
+        }
+
+        for label in &self.labels {
+            for &b in label {
+                match b {
+                    b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => {
+                        write!(f, "\\{}", b as char)?
+                    }
+                    0x21..=0x7e => write!(f, "{}", b as char)?,
+                    b => write!(f, "\\{b:03}")?,
+                }
+            }
+            f.write_str(".")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for DomainName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DomainName({self})")
+    }
+}
+
+impl PartialEq for DomainName {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DomainName {}
+
+impl Hash for DomainName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.labels.len().hash(state);
+        for label in &self.labels {
+            label.to_ascii_lowercase().hash(state);
+        }
+    }
+}
+
+impl PartialOrd for DomainName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DomainName {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.labels
+            .iter()
+            .rev()
+            .zip(other.labels.iter().rev())
+            .map(|(a, b)| {
+                a.iter()
+                    .map(u8::to_ascii_lowercase)
+                    .cmp(b.iter().map(u8::to_ascii_lowercase))
+            })
+            .find(|o| o.is_ne())
+            .unwrap_or_else(|| self.labels.len().cmp(&other.labels.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!(
+            name("www.example.com").labels(),
+            vec![b"www".as_slice(), b"example", b"com"]
+        );
+        assert_eq!(name("www.example.com."), name("www.example.com"));
+        assert!(name(".").is_root());
+        assert_eq!(name(".").to_string(), ".");
+        assert_eq!(name("www.example.com").to_string(), "www.example.com.");
+
+        let escaped = name(r"a\.b.c\032d\\.example");
+        assert_eq!(
+            escaped.labels(),
+            vec![b"a.b".as_slice(), b"c d\\", b"example"]
+        );
+        assert_eq!(escaped.to_string(), r"a\.b.c\032d\\.example.");
+        assert_eq!(name(&escaped.to_string()), escaped);
+
+        let binary = DomainName::from_labels(&[&[0, 0xff, b'x']]);
+        assert_eq!(binary.to_string(), r"\000\255x.");
+        assert_eq!(name(&binary.to_string()).labels(), binary.labels());
+
+        let origin = name("example.com");
+        assert_eq!(
+            DomainName::parse_relative("www", &origin).unwrap(),
+            name("www.example.com")
+        );
+        assert_eq!(
+            DomainName::parse_relative("www.example.org.", &origin).unwrap(),
+            name("www.example.org")
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let parse = |s: &str| s.parse::<DomainName>();
+
+        assert_eq!(parse(""), Err(NameParseError::EmptyLabel(String::new())));
+        assert!(matches!(parse("a..b"), Err(NameParseError::EmptyLabel(_))));
+        assert!(matches!(parse(".a"), Err(NameParseError::EmptyLabel(_))));
+        assert!(matches!(parse("a\\"), Err(NameParseError::BadEscape(_))));
+        assert!(matches!(parse("a\\256"), Err(NameParseError::BadEscape(_))));
+        assert!(matches!(
+            parse(&"a".repeat(64)),
+            Err(NameParseError::LabelTooLong(_))
+        ));
+        assert!(parse(&"a".repeat(63)).is_ok());
+        assert!(matches!(
+            parse(&vec!["a".repeat(63); 4].join(".")),
+            Err(NameParseError::NameTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        assert_eq!(name("CodeCrafters.IO"), name("codecrafters.io"));
+        assert_ne!(name("codecrafters.io"), name("codecrafters.com"));
+
+        let names = HashSet::from([name("CodeCrafters.IO")]);
+        assert!(names.contains(&name("codecrafters.io")));
+
+        // the original case is kept
+        assert_eq!(name("CodeCrafters.IO").to_string(), "CodeCrafters.IO.");
+    }
+
+    #[test]
+    fn test_canonical_order() {
+        // the example from RFC 4034 6.1
+        let ordered = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            r"zABC.a.EXAMPLE",
+            "z.example",
+            r"\001.z.example",
+            "*.z.example",
+            r"\200.z.example",
+        ]
+        .map(name);
+
+        let mut sorted = ordered.clone();
+        sorted.reverse();
+        sorted.sort();
+        assert_eq!(sorted, ordered);
+        assert!(DomainName::root() < name("example"));
+    }
+
+    #[test]
+    fn test_relationships() {
+        let www = name("www.example.com");
+
+        assert_eq!(www.parent(), Some(name("example.com")));
+        assert_eq!(DomainName::root().parent(), None);
+        assert_eq!(
+            www.ancestors().collect::<Vec<_>>(),
+            vec![
+                www.clone(),
+                name("example.com"),
+                name("com"),
+                DomainName::root()
+            ]
+        );
+        assert_eq!(name("example.com").child(b"*"), name("*.example.com"));
+
+        assert!(www.is_subdomain_of(&name("Example.com")));
+        assert!(www.is_subdomain_of(&www));
+        assert!(www.is_subdomain_of(&DomainName::root()));
+        assert!(!www.is_subdomain_of(&name("ample.com")));
+        assert!(!name("example.com").is_subdomain_of(&www));
+    }
+}
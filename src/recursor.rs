@@ -0,0 +1,867 @@
+use crate::dns_hdr::{
+    Answer, DNSHdr, Flags, OpCode, Query, RCode, RData, RRClass, RRType, MAX_CNAME_CHAIN,
+};
+use crate::name::DomainName;
+use crate::resolver::{Client, ResolverOptions};
+use crate::zone::{Record, ZoneRecords};
+use anyhow::{Context, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+// Minimised queries one step down the tree may take before the full name is
+// sent anyway, so that names with many labels stay cheap (RFC 9156 2.3)
+const MAX_MINIMISE_STEPS: usize = 10;
+
+/*
+QNAME minimisation (RFC 9156): the servers of a zone are only asked about the
+name one label below the zone, with type A, until they refer us further down
+or we reach the full name. Relaxed mode sends the full name after all when a
+minimised query gets NXDOMAIN or FORMERR, since some servers answer those for
+empty non-terminals. Strict mode takes NXDOMAIN to cover every name below
+(RFC 8020) and fails on FORMERR.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Minimisation {
+    Off,
+    Strict,
+    Relaxed,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RecursorOptions {
+    // the port every name server is reached on
+    pub port: u16,
+    // how deep lookups of name server addresses may nest
+    pub max_depth: usize,
+    // how many queries one question may take, nested lookups included
+    pub max_queries: usize,
+    pub minimise: Minimisation,
+}
+
+impl Default for RecursorOptions {
+    fn default() -> Self {
+        RecursorOptions {
+            port: 53,
+            max_depth: 4,
+            max_queries: 64,
+            minimise: Minimisation::Relaxed,
+        }
+    }
+}
+
+// The records of a response we keep once the message is gone
+#[derive(Debug, Default)]
+struct Reply {
+    rcode: u16,
+    aa: bool,
+    answers: Vec<(DomainName, Record)>,
+    authorities: Vec<(DomainName, Record)>,
+    additionals: Vec<(DomainName, Record)>,
+}
+
+impl Reply {
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let response = DNSHdr::from_bytes(bytes)?;
+        let owned = |records: &[Answer]| {
+            records
+                .iter()
+                .map(|a| {
+                    (
+                        DomainName::from_labels(&a.name),
+                        Record::new(a.ttl, &a.rdata),
+                    )
+                })
+                .collect()
+        };
+
+        Ok(Reply {
+            rcode: response.rcode(),
+            aa: response.flags.aa == 1,
+            answers: owned(&response.answers),
+            authorities: owned(&response.authorities),
+            additionals: owned(&response.additionals),
+        })
+    }
+
+    fn has_soa(&self) -> bool {
+        self.authorities
+            .iter()
+            .any(|(_, r)| r.rtype == RRType::SOA as u16)
+    }
+
+    // The zone cut and name servers a referral for name points to. The cut
+    // must be closer to name than zone, the zone that was asked.
+    fn referral(
+        &self,
+        zone: &DomainName,
+        name: &DomainName,
+    ) -> Option<(DomainName, Vec<DomainName>)> {
+        if self.rcode != RCode::OK as u16 || self.aa || !self.answers.is_empty() || self.has_soa() {
+            return None;
+        }
+
+        let ns = self
+            .authorities
+            .iter()
+            .filter_map(|(owner, r)| match r.rdata() {
+                RData::NS(target) => Some((owner, DomainName::from_labels(&target))),
+                _ => None,
+            })
+            .filter(|(owner, _)| {
+                name.is_subdomain_of(owner) && owner.is_subdomain_of(zone) && *owner != zone
+            })
+            .collect::<Vec<_>>();
+
+        let cut = ns.first()?.0.clone();
+        let targets = ns
+            .into_iter()
+            .filter(|(owner, _)| **owner == cut)
+            .map(|(_, target)| target)
+            .collect();
+        Some((cut, targets))
+    }
+
+    /*
+    Drops the records the servers of zone have no say over: anything outside
+    the zone, and answers that are not on the CNAME chain from name, the name
+    that was asked (RFC 5452 6). Otherwise a server could plant records for
+    names it was never asked about.
+    */
+    fn in_bailiwick(mut self, zone: &DomainName, name: &DomainName) -> Self {
+        let mut chain = vec![name.clone()];
+        for _ in 0..MAX_CNAME_CHAIN {
+            let owner = chain.last().unwrap();
+            let next = self.answers.iter().find_map(|(o, r)| match r.rdata() {
+                RData::CNAME(next) if o == owner => Some(DomainName::from_labels(&next)),
+                _ => None,
+            });
+            match next {
+                Some(next) if next.is_subdomain_of(zone) && !chain.contains(&next) => {
+                    chain.push(next)
+                }
+                _ => break,
+            }
+        }
+
+        self.answers
+            .retain(|(owner, _)| owner.is_subdomain_of(zone) && chain.contains(owner));
+        self.authorities
+            .retain(|(owner, _)| owner.is_subdomain_of(zone));
+        self.additionals
+            .retain(|(owner, _)| owner.is_subdomain_of(zone));
+        self
+    }
+
+    // The addresses the reply gives for name
+    fn addresses<'a>(
+        records: &'a [(DomainName, Record)],
+        name: &'a DomainName,
+    ) -> impl Iterator<Item = Ipv4Addr> + 'a {
+        records
+            .iter()
+            .filter(move |(owner, _)| owner == name)
+            .filter_map(|(_, r)| match r.rdata() {
+                RData::A(ip) => Some(ip),
+                _ => None,
+            })
+    }
+}
+
+/*
+Resolves names iteratively (RFC 1034 5.3.3): every question starts at the root
+servers from the hints and follows referrals down to a server that answers for
+the name. Name servers without glue are looked up the same way, and aliases
+that leave the zone are followed from the root again.
+
+A question may take at most max_queries queries, and lookups of name server
+addresses may only nest max_depth deep, so that referral loops and chains of
+glueless delegations end in an error.
+*/
+pub struct Recursor {
+    client: Client,
+    roots: Vec<Ipv4Addr>,
+    options: RecursorOptions,
+}
+
+// How far one question has got, kept apart from the recursor so that several
+// questions can be resolved at once
+#[derive(Debug, Default)]
+struct Progress {
+    // queries sent so far, nested lookups included
+    sent: usize,
+    // every query and what came of it
+    trace: Vec<String>,
+}
+
+impl Recursor {
+    pub fn new(
+        hints: &ZoneRecords,
+        resolver: ResolverOptions,
+        options: RecursorOptions,
+    ) -> Result<Self> {
+        Ok(Self {
+            client: Client::new(resolver),
+            roots: root_servers(hints).context("No root server addresses in the hints")?,
+            options,
+        })
+    }
+
+    // Resolves the question and returns the outcome as a response message
+    pub fn resolve(&self, query: &Query) -> Result<Vec<u8>> {
+        let mut progress = Progress::default();
+        let reply = self.resolve_name(&query.domain(), query.qtype, 0, &mut progress);
+
+        eprintln!(
+            "Resolving {} took {} queries:",
+            query.domain(),
+            progress.sent
+        );
+        for step in &progress.trace {
+            eprintln!("  {step}");
+        }
+        let reply = reply?;
+
+        let mut response = DNSHdr::new(
+            0,
+            Flags {
+                qr: 1,
+                opcode: OpCode::QUERY as u8,
+                aa: 0,
+                tc: 0,
+                rd: 1,
+                ra: 1,
+                rcode: 0,
+            },
+            vec![query.clone()],
+            reply.answers.iter().map(to_answer).collect(),
+        );
+        response.set_rcode(reply.rcode);
+        response.authorities = reply.authorities.iter().map(to_answer).collect();
+
+        Ok(response.to_bytes().to_vec())
+    }
+
+    // Resolves name and the aliases it leads to, the answers of every step
+    // end up in the answers of the last one
+    fn resolve_name(
+        &self,
+        name: &DomainName,
+        qtype: u16,
+        depth: usize,
+        progress: &mut Progress,
+    ) -> Result<Reply> {
+        let mut chain = vec![];
+        let mut qname = name.clone();
+
+        for _ in 0..=MAX_CNAME_CHAIN {
+            let mut reply = self.iterate(&qname, qtype, depth, progress)?;
+
+            // the server may have followed some of the aliases itself
+            let mut target = qname.clone();
+            for _ in 0..reply.answers.len() {
+                let next = reply.answers.iter().find_map(|(owner, r)| match r.rdata() {
+                    RData::CNAME(next) if *owner == target => Some(DomainName::from_labels(&next)),
+                    _ => None,
+                });
+                match next {
+                    Some(next) => target = next,
+                    None => break,
+                }
+            }
+
+            let answered = qtype == RRType::CNAME as u16
+                || reply
+                    .answers
+                    .iter()
+                    .any(|(owner, r)| *owner == target && r.rtype == qtype);
+            chain.append(&mut reply.answers);
+
+            if answered || target == qname || reply.rcode != RCode::OK as u16 || reply.has_soa() {
+                reply.answers = chain;
+                return Ok(reply);
+            }
+            qname = target;
+        }
+
+        anyhow::bail!("CNAME chain from {name} is too long")
+    }
+
+    // Follows referrals from the root down to the servers for name, and
+    // returns what they say about it
+    fn iterate(
+        &self,
+        name: &DomainName,
+        qtype: u16,
+        depth: usize,
+        progress: &mut Progress,
+    ) -> Result<Reply> {
+        let mut zone = DomainName::root();
+        let mut servers = self.roots.clone();
+        let labels = name.labels().len();
+        // how many labels of name the next minimised query shows
+        let mut shown = 1;
+        let mut steps = 0;
+        let mut minimise = self.options.minimise != Minimisation::Off;
+
+        loop {
+            let minimised = minimise && shown < labels;
+            let (qname, qt) = match minimised {
+                true => (
+                    name.ancestors()
+                        .nth(labels - shown)
+                        .expect("shown < labels"),
+                    RRType::A as u16,
+                ),
+                false => (name.clone(), qtype),
+            };
+
+            let reply = self
+                .ask(&servers, &qname, qt, progress)?
+                .in_bailiwick(&zone, &qname);
+            progress.trace.push(format!(
+                "{zone} {qname} type {qt}{}: rcode {}",
+                if minimised { " (minimised)" } else { "" },
+                reply.rcode
+            ));
+
+            if [RCode::NameError as u16, RCode::FmtError as u16].contains(&reply.rcode) && minimised
+            {
+                match self.options.minimise {
+                    Minimisation::Relaxed => {
+                        progress
+                            .trace
+                            .push(format!("{zone} falling back to {name}"));
+                        minimise = false;
+                        continue;
+                    }
+                    _ if reply.rcode == RCode::NameError as u16 => return Ok(reply),
+                    _ => anyhow::bail!("Servers of {zone} refused the minimised {qname}"),
+                }
+            }
+            if reply.rcode == RCode::FmtError as u16 {
+                anyhow::bail!("Servers of {zone} rejected {qname} as malformed");
+            }
+
+            let Some((cut, ns)) = reply.referral(&zone, name) else {
+                // the name goes on inside this zone
+                if minimised {
+                    shown += 1;
+                    steps += 1;
+                    minimise = steps < MAX_MINIMISE_STEPS;
+                    continue;
+                }
+                // neither an answer nor a step down the tree
+                if reply.rcode == RCode::OK as u16
+                    && !reply.aa
+                    && reply.answers.is_empty()
+                    && !reply.has_soa()
+                {
+                    anyhow::bail!("Lame referral for {name} from the servers of {zone}");
+                }
+                return Ok(reply);
+            };
+            progress.trace.push(format!("{zone} refers to {cut}"));
+
+            // glue is only trusted for names in the zone that sent it
+            servers = ns
+                .iter()
+                .filter(|ns| ns.is_subdomain_of(&zone))
+                .flat_map(|ns| Reply::addresses(&reply.additionals, ns))
+                .collect();
+            if servers.is_empty() {
+                servers = self.lookup_servers(&cut, &ns, depth, progress)?;
+            }
+            shown = cut.labels().len() + 1;
+            steps = 0;
+            zone = cut;
+        }
+    }
+
+    // Looks up the addresses of name servers that came without glue
+    fn lookup_servers(
+        &self,
+        cut: &DomainName,
+        ns: &[DomainName],
+        depth: usize,
+        progress: &mut Progress,
+    ) -> Result<Vec<Ipv4Addr>> {
+        if depth >= self.options.max_depth {
+            anyhow::bail!(
+                "Name servers of {cut} are more than {} lookups deep",
+                self.options.max_depth
+            );
+        }
+
+        for name in ns {
+            match self.resolve_name(name, RRType::A as u16, depth + 1, progress) {
+                Ok(reply) => {
+                    let servers = Reply::addresses(&reply.answers, name).collect::<Vec<_>>();
+                    if !servers.is_empty() {
+                        return Ok(servers);
+                    }
+                }
+                Err(e) => eprintln!("No address for {name}: {e:#}"),
+            }
+        }
+
+        anyhow::bail!("No address for any name server of {cut}")
+    }
+
+    // Asks the servers in turn until one gives an answer, a referral or
+    // rejects the question
+    fn ask(
+        &self,
+        servers: &[Ipv4Addr],
+        name: &DomainName,
+        qtype: u16,
+        progress: &mut Progress,
+    ) -> Result<Reply> {
+        let query = Query {
+            name: name.labels(),
+            qtype,
+            qclass: RRClass::IN as u16,
+        };
+
+        for ip in servers {
+            if progress.sent >= self.options.max_queries {
+                anyhow::bail!(
+                    "Gave up on {name} after {} queries",
+                    self.options.max_queries
+                );
+            }
+            progress.sent += 1;
+
+            let server = SocketAddr::new(IpAddr::V4(*ip), self.options.port);
+            let reply = self
+                .client
+                .query(server, &query, 0)
+                .and_then(|bytes| Reply::from_bytes(&bytes));
+            match reply {
+                Ok(reply)
+                    if [
+                        RCode::OK as u16,
+                        RCode::NameError as u16,
+                        RCode::FmtError as u16,
+                    ]
+                    .contains(&reply.rcode) =>
+                {
+                    return Ok(reply);
+                }
+                Ok(reply) => eprintln!("{server} answered {name} with rcode {}", reply.rcode),
+                Err(e) => eprintln!("{server} failed for {name}: {e:#}"),
+            }
+        }
+
+        anyhow::bail!("No name server answered for {name}")
+    }
+}
+
+// The addresses of the root name servers in a hints file: the root NS
+// records and the A records of their targets
+pub fn root_servers(hints: &ZoneRecords) -> Option<Vec<Ipv4Addr>> {
+    let roots = hints
+        .iter()
+        .filter(|(owner, _)| owner.is_root())
+        .filter_map(|(_, r)| match r.rdata() {
+            RData::NS(target) => Some(DomainName::from_labels(&target)),
+            _ => None,
+        })
+        .flat_map(|ns| Reply::addresses(hints, &ns).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    (!roots.is_empty()).then_some(roots)
+}
+
+fn to_answer((name, r): &(DomainName, Record)) -> Answer<'_> {
+    Answer::new(name.labels(), RRClass::IN, r.ttl, r.rdata())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authority::Zone;
+    use crate::dns_server::DNSServer;
+    use crate::testing::{mock_upstream_at, response};
+    use crate::zone;
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::Duration;
+
+    const SOA: &str = "@ SOA ns hostmaster 1 3600 600 86400 300";
+
+    // Glue only carries addresses, so every server of the hierarchy listens
+    // on the same port of its own loopback address
+    const ROOT: &str = "
+        $TTL 3600
+        {SOA}
+        @ NS a.root.
+        a.root. A 127.0.0.1
+        test. NS ns.test.
+        other. NS ns.test.
+        ns.test. A 127.0.0.2
+    ";
+    // serves test. and other.
+    const TLD: &str = "
+        $TTL 3600
+        {SOA}
+        @ NS ns.test.
+        ns.test. A 127.0.0.2
+        leaf NS ns.leaf
+        ns.leaf A 127.0.0.3
+        glueless NS ns.other.
+        loop NS ns.loop.other.
+        nx NS ns.nx
+        ns.nx A 127.0.0.4
+        fe NS ns.fe
+        ns.fe A 127.0.0.5
+    ";
+    const OTHER: &str = "
+        $TTL 3600
+        {SOA}
+        @ NS ns.test.
+        ns A 127.0.0.3
+        host A 192.0.2.3
+        loop NS ns.loop.test.
+    ";
+    // serves leaf.test. and glueless.test.
+    const LEAF: &str = "
+        $TTL 3600
+        {SOA}
+        @ NS ns
+        ns A 127.0.0.3
+        www A 192.0.2.1
+        deep.ent A 192.0.2.4
+        alias CNAME host.other.
+    ";
+    const GLUELESS: &str = "
+        $TTL 3600
+        {SOA}
+        @ NS ns.other.
+        www A 192.0.2.2
+    ";
+
+    fn serve(addr: &str, zones: &[(&str, &str)]) -> Result<SocketAddr> {
+        let server = DNSServer::new(addr, None)?;
+        for (origin, src) in zones {
+            let origin: DomainName = origin.parse()?;
+            let src = src
+                .lines()
+                .map(|l| l.trim().replace("{SOA}", SOA))
+                .collect::<Vec<_>>()
+                .join("\n");
+            server.add_zone(Zone::new(
+                origin.clone(),
+                zone::parse(&src, "test.zone", &origin)?,
+            )?);
+        }
+        let addr = server.local_addr()?;
+        thread::spawn(move || server.start());
+
+        Ok(addr)
+    }
+
+    // Starts the hierarchy and returns the port it listens on
+    fn hierarchy() -> Result<u16> {
+        let port = serve("127.0.0.1:0", &[(".", ROOT)])?.port();
+        serve(
+            &format!("127.0.0.2:{port}"),
+            &[("test", TLD), ("other", OTHER)],
+        )?;
+        serve(
+            &format!("127.0.0.3:{port}"),
+            &[("leaf.test", LEAF), ("glueless.test", GLUELESS)],
+        )?;
+
+        Ok(port)
+    }
+
+    fn recursor(port: u16, max_depth: usize, max_queries: usize) -> Result<Recursor> {
+        let hints = zone::parse(
+            "$TTL 3600000\n. NS a.root.\na.root. A 127.0.0.1\n",
+            "hints",
+            &DomainName::root(),
+        )?;
+        Recursor::new(
+            &hints,
+            ResolverOptions {
+                timeout: Duration::from_millis(100),
+                retries: 1,
+                ..ResolverOptions::default()
+            },
+            RecursorOptions {
+                port,
+                max_depth,
+                max_queries,
+                ..RecursorOptions::default()
+            },
+        )
+    }
+
+    fn minimising(port: u16, mode: Minimisation) -> Result<Recursor> {
+        let mut recursor = recursor(port, 4, 64)?;
+        recursor.options.minimise = mode;
+        Ok(recursor)
+    }
+
+    // A server that only answers names starting with www, and answers
+    // anything else with rcode the way broken servers do
+    fn broken_server(addr: &str, rcode: RCode) -> Result<()> {
+        let rcode = rcode as u8;
+
+        mock_upstream_at(addr, move |request, _| {
+            let q = &request.queries[0];
+            let www = q.name[0] == b"www";
+            let mut response = response(
+                request,
+                match www {
+                    true => vec![Answer::new(
+                        q.name.clone(),
+                        RRClass::IN,
+                        60,
+                        RData::A(Ipv4Addr::new(192, 0, 2, 9)),
+                    )],
+                    false => vec![],
+                },
+            );
+            response.flags.aa = 1;
+            response.flags.rcode = if www { 0 } else { rcode };
+            vec![response.to_bytes().to_vec()]
+        })?;
+
+        Ok(())
+    }
+
+    fn resolve(recursor: &Recursor, name: &str) -> Result<(u16, Vec<RData<'static>>)> {
+        let name: DomainName = name.parse()?;
+        let query = Query {
+            name: name.labels(),
+            qtype: RRType::A as u16,
+            qclass: RRClass::IN as u16,
+        };
+        let response = recursor.resolve(&query)?;
+        let response = DNSHdr::from_bytes(&response)?;
+
+        let rdata = response
+            .answers
+            .iter()
+            .map(|a| match &a.rdata {
+                RData::A(ip) => RData::A(*ip),
+                RData::CNAME(_) => RData::CNAME(vec![]),
+                other => panic!("unexpected {other:?}"),
+            })
+            .collect();
+        Ok((response.rcode(), rdata))
+    }
+
+    // The queries the recursor sends for the A records of name
+    fn trace(recursor: &Recursor, name: &str) -> Result<Vec<String>> {
+        let mut progress = Progress::default();
+        recursor.resolve_name(&name.parse()?, RRType::A as u16, 0, &mut progress)?;
+        Ok(progress.trace)
+    }
+
+    #[test]
+    fn test_follows_referrals() -> Result<()> {
+        let recursor = recursor(hierarchy()?, 4, 64)?;
+
+        let (rcode, answers) = resolve(&recursor, "www.leaf.test")?;
+        assert_eq!(rcode, RCode::OK as u16);
+        assert_eq!(answers, vec![RData::A(Ipv4Addr::new(192, 0, 2, 1))]);
+
+        let response = recursor.resolve(&Query {
+            name: vec![b"missing", b"leaf", b"test"],
+            qtype: RRType::A as u16,
+            qclass: RRClass::IN as u16,
+        })?;
+        let response = DNSHdr::from_bytes(&response)?;
+        assert_eq!(response.rcode(), RCode::NameError as u16);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(
+            response.authorities[0].name,
+            vec![b"leaf".as_slice(), b"test"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_glueless_delegation() -> Result<()> {
+        let port = hierarchy()?;
+
+        let recursor = recursor(port, 4, 64)?;
+        let (rcode, answers) = resolve(&recursor, "www.glueless.test")?;
+        assert_eq!(rcode, RCode::OK as u16);
+        assert_eq!(answers, vec![RData::A(Ipv4Addr::new(192, 0, 2, 2))]);
+
+        // the address of ns.other. is one lookup deep
+        let recursor = self::recursor(port, 0, 64)?;
+        assert!(resolve(&recursor, "www.glueless.test").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cname_across_zones() -> Result<()> {
+        let recursor = recursor(hierarchy()?, 4, 64)?;
+
+        let (rcode, answers) = resolve(&recursor, "alias.leaf.test")?;
+        assert_eq!(rcode, RCode::OK as u16);
+        assert_eq!(
+            answers,
+            vec![RData::CNAME(vec![]), RData::A(Ipv4Addr::new(192, 0, 2, 3))]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_drops_out_of_bailiwick_records() -> Result<()> {
+        let port = hierarchy()?;
+        // the server of nx.test also speaks for names it has no say over
+        mock_upstream_at(&format!("127.0.0.4:{port}"), |request, _| {
+            let spoofed =
+                |name| Answer::new(name, RRClass::IN, 60, RData::A(Ipv4Addr::new(6, 6, 6, 6)));
+            let answers = vec![
+                spoofed(vec![b"www", b"leaf", b"test"]),
+                Answer::new(
+                    request.queries[0].name.clone(),
+                    RRClass::IN,
+                    60,
+                    RData::A(Ipv4Addr::new(192, 0, 2, 9)),
+                ),
+                spoofed(vec![b"other", b"nx", b"test"]),
+            ];
+            let mut response = response(request, answers);
+            response.flags.aa = 1;
+            response.authorities.push(Answer::new(
+                vec![b"test"],
+                RRClass::IN,
+                60,
+                RData::NS(vec![b"ns", b"evil"]),
+            ));
+            vec![response.to_bytes().to_vec()]
+        })?;
+
+        let recursor = recursor(port, 4, 64)?;
+        let response = recursor.resolve(&Query {
+            name: vec![b"www", b"nx", b"test"],
+            qtype: RRType::A as u16,
+            qclass: RRClass::IN as u16,
+        })?;
+        let response = DNSHdr::from_bytes(&response)?;
+        assert_eq!(response.rcode(), RCode::OK as u16);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(
+            response.answers[0].rdata,
+            RData::A(Ipv4Addr::new(192, 0, 2, 9))
+        );
+        assert!(response.authorities.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_limits() -> Result<()> {
+        let port = hierarchy()?;
+
+        // root, TLD and leaf
+        let recursor = self::recursor(port, 4, 2)?;
+        assert!(resolve(&recursor, "www.leaf.test").is_err());
+        let recursor = self::recursor(port, 4, 3)?;
+        assert!(resolve(&recursor, "www.leaf.test").is_ok());
+
+        // the servers of loop.test and loop.other are each other's names
+        let recursor = self::recursor(port, 4, 64)?;
+        assert!(resolve(&recursor, "www.loop.test").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_minimised_steps() -> Result<()> {
+        let port = hierarchy()?;
+
+        let recursor = minimising(port, Minimisation::Strict)?;
+        assert_eq!(
+            trace(&recursor, "www.leaf.test")?,
+            vec![
+                ". test. type 1 (minimised): rcode 0",
+                ". refers to test.",
+                "test. leaf.test. type 1 (minimised): rcode 0",
+                "test. refers to leaf.test.",
+                "leaf.test. www.leaf.test. type 1: rcode 0",
+            ]
+        );
+
+        // an empty non-terminal inside the zone takes one more step
+        let (rcode, answers) = resolve(&recursor, "deep.ent.leaf.test")?;
+        assert_eq!(rcode, RCode::OK as u16);
+        assert_eq!(answers, vec![RData::A(Ipv4Addr::new(192, 0, 2, 4))]);
+        assert!(trace(&recursor, "deep.ent.leaf.test")?
+            .contains(&"leaf.test. ent.leaf.test. type 1 (minimised): rcode 0".to_string()));
+
+        let recursor = minimising(port, Minimisation::Off)?;
+        assert_eq!(
+            trace(&recursor, "www.leaf.test")?[0],
+            ". www.leaf.test. type 1: rcode 0"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_minimisation_modes() -> Result<()> {
+        let port = hierarchy()?;
+        broken_server(&format!("127.0.0.4:{port}"), RCode::NameError)?;
+        broken_server(&format!("127.0.0.5:{port}"), RCode::FmtError)?;
+        let found = (
+            RCode::OK as u16,
+            vec![RData::A(Ipv4Addr::new(192, 0, 2, 9))],
+        );
+
+        // relaxed mode retries with the full name
+        let recursor = minimising(port, Minimisation::Relaxed)?;
+        assert_eq!(resolve(&recursor, "www.a.nx.test")?, found);
+        assert!(trace(&recursor, "www.a.nx.test")?
+            .contains(&"nx.test. falling back to www.a.nx.test.".to_string()));
+        assert_eq!(resolve(&recursor, "www.a.fe.test")?, found);
+
+        // strict mode believes the broken servers
+        let recursor = minimising(port, Minimisation::Strict)?;
+        assert_eq!(
+            resolve(&recursor, "www.a.nx.test")?,
+            (RCode::NameError as u16, vec![])
+        );
+        assert!(resolve(&recursor, "www.a.fe.test").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_root_servers() -> Result<()> {
+        let hints = zone::parse(
+            ". 3600000 NS a.root-servers.net.\n\
+             . 3600000 NS b.root-servers.net.\n\
+             a.root-servers.net. 3600000 A 198.41.0.4\n\
+             b.root-servers.net. 3600000 A 170.247.170.2\n\
+             c.root-servers.net. 3600000 A 192.33.4.12\n",
+            "named.root",
+            &DomainName::root(),
+        )?;
+        assert_eq!(
+            root_servers(&hints),
+            Some(vec![
+                Ipv4Addr::new(198, 41, 0, 4),
+                Ipv4Addr::new(170, 247, 170, 2)
+            ])
+        );
+        assert_eq!(root_servers(&vec![]), None);
+
+        // nothing listens on the root server
+        let silent = UdpSocket::bind("127.0.0.1:0")?;
+        let recursor = recursor(silent.local_addr()?.port(), 4, 64)?;
+        assert!(resolve(&recursor, "www.leaf.test").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,813 @@
+use crate::dns_hdr::{DNSHdr, Edns, Flags, OpCode, Query, RCode, EDNS_UDP_SIZE};
+use crate::tcp::{read_message, write_message};
+use anyhow::{Context, Result};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Random ports tried for a query before leaving the choice to the system
+const MAX_BIND_ATTEMPTS: usize = 8;
+
+// Which upstream a question goes to first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    // in the order they were given
+    Failover,
+    // each question starts at the next upstream
+    RoundRobin,
+    Random,
+    // the lowest smoothed round trip time, upstreams not measured yet first
+    Fastest,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ResolverOptions {
+    // how long to wait for the first attempt, doubled on every retry
+    pub timeout: Duration,
+    // how many times a query is resent after the first attempt timed out
+    pub retries: u32,
+    pub policy: Policy,
+    // failures in a row after which an upstream is marked down
+    pub max_failures: u32,
+    // how long an upstream stays down before it is tried again
+    pub down_time: Duration,
+}
+
+impl Default for ResolverOptions {
+    fn default() -> Self {
+        ResolverOptions {
+            timeout: Duration::from_secs(1),
+            retries: 2,
+            policy: Policy::Failover,
+            max_failures: 3,
+            down_time: Duration::from_secs(30),
+        }
+    }
+}
+
+// Sends queries to name servers and waits for the matching responses
+pub struct Client {
+    options: ResolverOptions,
+}
+
+impl Client {
+    pub fn new(options: ResolverOptions) -> Self {
+        Self { options }
+    }
+
+    // Asks the server the question and returns its validated response
+    // message, whatever its RCODE
+    pub fn query(&self, server: SocketAddr, query: &Query, rd: u8) -> Result<Vec<u8>> {
+        let mut rng = rand::thread_rng();
+
+        // create a dns request
+        let id = rng.gen();
+        let flags = Flags {
+            qr: 0,
+            opcode: OpCode::QUERY as u8,
+            aa: 0,
+            tc: 0,
+            rd,
+            ra: 0,
+            rcode: 0,
+        };
+        let mut req = DNSHdr::new(id, flags, vec![query.clone()], vec![]);
+        req.edns = Some(Edns::new(EDNS_UDP_SIZE));
+        eprintln!("Sending {req:?} to {server}");
+
+        let response = self.exchange(server, &req)?;
+        match DNSHdr::from_bytes(&response) {
+            Ok(answer) => eprintln!(
+                "Received DNS answer: rcode={} {:?}",
+                answer.rcode(),
+                answer
+                    .answers
+                    .iter()
+                    .map(|a| format!(
+                        "{:?} ttl={} qclass={} qtype={}",
+                        a.rdata, a.ttl, a.qclass, a.qtype
+                    ))
+                    .collect::<Vec<_>>()
+            ),
+            Err(e) => anyhow::bail!("Malformed response from {server}: {e}"),
+        }
+
+        Ok(response)
+    }
+
+    // Sends the query over UDP and repeats it over TCP when the server could
+    // not fit the whole response in a datagram
+    fn exchange(&self, server: SocketAddr, req: &DNSHdr) -> Result<Vec<u8>> {
+        let bytes = req.to_bytes();
+        let mut buf = [0; EDNS_UDP_SIZE as usize];
+        let mut timeout = self.options.timeout;
+        // left unconnected so we see, and can reject, datagrams from anyone else
+        let socket = random_port_socket()?;
+
+        for attempt in 0..=self.options.retries {
+            // send to resolver
+            socket.send_to(&bytes, server)?;
+
+            // wait for a response to this query, ignoring anything else
+            let deadline = Instant::now() + timeout;
+            let size = loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break None;
+                }
+                socket.set_read_timeout(Some(remaining))?;
+
+                match socket.recv_from(&mut buf) {
+                    Ok((size, source)) if source == server && matches(req, &buf[..size]) => {
+                        break Some(size);
+                    }
+                    Ok((size, source)) => {
+                        eprintln!("Discarding unexpected {size} bytes from {source}");
+                    }
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                        break None;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            };
+
+            let Some(size) = size else {
+                eprintln!(
+                    "No response from {} within {:?} (attempt {})",
+                    server,
+                    timeout,
+                    attempt + 1
+                );
+                timeout *= 2;
+                continue;
+            };
+            println!("Received {} bytes from {} {:?}", size, server, &buf[..size]);
+
+            if !truncated(&buf[..size]) {
+                return Ok(buf[..size].to_vec());
+            }
+
+            eprintln!("Truncated response, retrying over TCP to {}", server);
+            let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+            stream.set_read_timeout(Some(timeout))?;
+            write_message(&mut stream, &bytes)?;
+
+            let response = read_message(&mut stream)?;
+            if !matches(req, &response) {
+                anyhow::bail!("TCP response from {} does not match the query", server);
+            }
+            return Ok(response);
+        }
+
+        anyhow::bail!(
+            "No response from {} after {} attempts",
+            server,
+            self.options.retries + 1
+        )
+    }
+}
+
+/*
+Every query goes out from a fresh socket on a random port, so that a spoofed
+response has to guess the port as well as the ID (RFC 5452 9.2). Should the
+ports we pick all be taken, the system picks one.
+*/
+fn random_port_socket() -> Result<UdpSocket> {
+    let mut rng = rand::thread_rng();
+    for _ in 0..MAX_BIND_ATTEMPTS {
+        if let Ok(socket) = UdpSocket::bind(("0.0.0.0", rng.gen_range(1024..=u16::MAX))) {
+            return Ok(socket);
+        }
+    }
+
+    UdpSocket::bind("0.0.0.0:0").context("Failed to bind to address")
+}
+
+// An upstream resolver and what we learned about it
+#[derive(Debug)]
+struct Upstream {
+    addr: SocketAddr,
+    // smoothed round trip time (RFC 6298)
+    srtt: Option<Duration>,
+    failures: u32,
+    down_until: Option<Instant>,
+}
+
+impl Upstream {
+    fn is_up(&self, now: Instant) -> bool {
+        self.down_until.map_or(true, |until| now >= until)
+    }
+
+    fn failed(&mut self, options: &ResolverOptions) {
+        self.failures += 1;
+        if self.failures >= options.max_failures {
+            eprintln!("Marking upstream {} down", self.addr);
+            self.down_until = Some(Instant::now() + options.down_time);
+        }
+    }
+}
+
+/*
+Forwards questions to upstream resolvers. The policy picks which upstream a
+question goes to first, and the others are tried in turn when it fails.
+Upstreams that fail max_failures times in a row are marked down and only used
+once the healthy ones have failed too, until down_time has passed and the
+next question probes them again.
+*/
+pub struct Resolver {
+    client: Client,
+    // only locked to pick an upstream and to record how it did, never while
+    // a query is out, so that questions are forwarded concurrently
+    upstreams: Mutex<Vec<Upstream>>,
+    // where the next round robin starts
+    next: AtomicUsize,
+}
+
+impl Resolver {
+    pub fn new<A: ToSocketAddrs>(addrs: &[A], options: ResolverOptions) -> Result<Self> {
+        let mut upstreams = vec![];
+        for addr in addrs {
+            upstreams.push(Upstream {
+                addr: addr
+                    .to_socket_addrs()?
+                    .next()
+                    .context("No address for upstream")?,
+                srtt: None,
+                failures: 0,
+                down_until: None,
+            });
+        }
+        if upstreams.is_empty() {
+            anyhow::bail!("No upstream given");
+        }
+
+        Ok(Self {
+            client: Client::new(options),
+            upstreams: Mutex::new(upstreams),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    // Forwards the question as is and returns the first validated upstream
+    // response message. A SERVFAIL or REFUSED counts against the upstream
+    // like a timeout, and is only returned when every upstream has failed.
+    pub fn resolve(&self, query: &Query) -> Result<Vec<u8>> {
+        let mut last_error = None;
+        let mut last_failed = None;
+
+        for i in self.order(Instant::now()) {
+            let addr = self.upstreams.lock().unwrap()[i].addr;
+            let start = Instant::now();
+            let response = self.client.query(addr, query, 1);
+
+            let mut upstreams = self.upstreams.lock().unwrap();
+            let upstream = &mut upstreams[i];
+            match response {
+                Ok(response) if !upstream_failed(&response) => {
+                    let rtt = start.elapsed();
+                    upstream.srtt = Some(upstream.srtt.map_or(rtt, |srtt| (srtt * 7 + rtt) / 8));
+                    upstream.failures = 0;
+                    upstream.down_until = None;
+                    return Ok(response);
+                }
+                Ok(response) => {
+                    eprintln!(
+                        "Upstream {} answered with rcode {}",
+                        upstream.addr,
+                        response[3] & 0b1111
+                    );
+                    upstream.failed(&self.client.options);
+                    last_failed = Some(response);
+                }
+                Err(e) => {
+                    eprintln!("Upstream {} failed: {e:#}", upstream.addr);
+                    upstream.failed(&self.client.options);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        match last_failed {
+            Some(response) => Ok(response),
+            None => Err(last_error.expect("there is at least one upstream")),
+        }
+    }
+
+    // The upstreams to try, by index: those that are up in the order of the
+    // policy, then those that are down, the soonest back first
+    fn order(&self, now: Instant) -> Vec<usize> {
+        let upstreams = self.upstreams.lock().unwrap();
+        let (mut up, mut down): (Vec<_>, Vec<_>) =
+            (0..upstreams.len()).partition(|&i| upstreams[i].is_up(now));
+
+        match self.client.options.policy {
+            Policy::Failover => {}
+            Policy::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                if !up.is_empty() {
+                    let start = next % up.len();
+                    up.rotate_left(start);
+                }
+            }
+            Policy::Random => up.shuffle(&mut rand::thread_rng()),
+            Policy::Fastest => up.sort_by_key(|&i| upstreams[i].srtt.unwrap_or_default()),
+        }
+        down.sort_by_key(|&i| upstreams[i].down_until);
+
+        up.extend(down);
+        up
+    }
+}
+
+// A response only belongs to our query if it is a response, carries the same
+// ID and repeats the question exactly; anything else may be a spoofing attempt
+fn matches(req: &DNSHdr, response: &[u8]) -> bool {
+    match DNSHdr::question_from_bytes(response) {
+        Ok(response) => {
+            response.flags.qr == 1
+                && response.id == req.id
+                && response.queries.len() == req.queries.len()
+                && response
+                    .queries
+                    .iter()
+                    .zip(req.queries.iter())
+                    .all(|(a, b)| a.same_question(b))
+        }
+        Err(_) => false,
+    }
+}
+
+// SERVFAIL and REFUSED tell more about the upstream than about the name, so
+// another upstream may well do better
+fn upstream_failed(response: &[u8]) -> bool {
+    response.get(3).is_some_and(|flags_l| {
+        [RCode::ServerFailure as u8, RCode::Refused as u8].contains(&(flags_l & 0b1111))
+    })
+}
+
+// Checks the TC bit straight from the header, since a truncated response may
+// be cut in the middle of a record and fail to decode
+fn truncated(response: &[u8]) -> bool {
+    response
+        .get(2)
+        .is_some_and(|flags_h| flags_h & 0b0000_0010 != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_hdr::{Answer, RCode, RData, RRClass, RRType};
+    use crate::testing::{mock_upstream, response};
+    use std::net::Ipv4Addr;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn resolve_a(resolver: &Resolver, name: Vec<&[u8]>) -> Result<(u32, Ipv4Addr)> {
+        let query = Query {
+            name,
+            qtype: RRType::A as u16,
+            qclass: RRClass::IN as u16,
+        };
+        let response = resolver.resolve(&query)?;
+        let response = DNSHdr::from_bytes(&response)?;
+
+        response
+            .answers
+            .iter()
+            .find_map(|a| match a.rdata {
+                RData::A(ip) => Some((a.ttl, ip)),
+                _ => None,
+            })
+            .context("no A record")
+    }
+
+    fn options() -> ResolverOptions {
+        ResolverOptions {
+            timeout: Duration::from_millis(50),
+            retries: 2,
+            ..ResolverOptions::default()
+        }
+    }
+
+    fn answer(request: &DNSHdr, ip: Ipv4Addr) -> Vec<u8> {
+        let answers = vec![Answer::new(
+            request.queries[0].name.clone(),
+            RRClass::IN,
+            60,
+            RData::A(ip),
+        )];
+        response(request, answers).to_bytes().to_vec()
+    }
+
+    // An upstream that ignores the first `drop` queries and answers the rest
+    // after `delay`
+    fn flaky_upstream(drop: usize, delay: Duration) -> Result<SocketAddr> {
+        let mut seen = 0;
+        mock_upstream(move |request, _| {
+            seen += 1;
+            if seen <= drop {
+                return vec![];
+            }
+            thread::sleep(delay);
+            vec![answer(request, Ipv4Addr::new(10, 0, 0, 1))]
+        })
+    }
+
+    // An upstream that answers every query with 10.0.0.<last> after delay
+    fn answering_upstream(last: u8, delay: Duration) -> Result<SocketAddr> {
+        mock_upstream(move |request, _| {
+            thread::sleep(delay);
+            vec![answer(request, Ipv4Addr::new(10, 0, 0, last))]
+        })
+    }
+
+    // The last octet of the address in the answer, telling which upstream
+    // answered
+    fn answered_by(resolver: &Resolver) -> Result<u8> {
+        let (_, ip) = resolve_a(resolver, vec![b"example", b"com"])?;
+        Ok(ip.octets()[3])
+    }
+
+    fn with_policy(policy: Policy) -> ResolverOptions {
+        ResolverOptions {
+            policy,
+            ..options()
+        }
+    }
+
+    // An upstream that only answers truncated over UDP and in full over TCP
+    fn truncating_upstream() -> Result<SocketAddr> {
+        let addr = mock_upstream(|request, _| {
+            let mut truncated = response(request, vec![]);
+            truncated.flags.tc = 1;
+            vec![truncated.to_bytes().to_vec()]
+        })?;
+        let listener = TcpListener::bind(addr)?;
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let req = read_message(&mut stream).unwrap();
+            let request = DNSHdr::from_bytes(&req).unwrap();
+            let mut response = response(&request, vec![]);
+            response.answers = (0..100)
+                .map(|i| {
+                    Answer::new(
+                        request.queries[0].name.clone(),
+                        RRClass::IN,
+                        60,
+                        RData::A(Ipv4Addr::new(10, 0, 0, i)),
+                    )
+                })
+                .collect();
+            write_message(&mut stream, &response.to_bytes()).unwrap();
+        });
+
+        Ok(addr)
+    }
+
+    #[test]
+    fn test_truncated_retries_over_tcp() -> Result<()> {
+        let addr = truncating_upstream()?;
+        let resolver = Resolver::new(&[addr], options())?;
+
+        let (ttl, ip) = resolve_a(&resolver, vec![b"big", b"example", b"com"])?;
+        assert_eq!(ttl, 60);
+        assert_eq!(ip, Ipv4Addr::new(10, 0, 0, 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_flag() {
+        assert!(truncated(&[0, 1, 0b1000_0010, 0]));
+        assert!(!truncated(&[0, 1, 0b1000_0000, 0]));
+        assert!(!truncated(&[0, 1]));
+    }
+
+    #[test]
+    fn test_retries_after_dropped_packets() -> Result<()> {
+        let addr = flaky_upstream(2, Duration::ZERO)?;
+        let resolver = Resolver::new(&[addr], options())?;
+
+        let (_, ip) = resolve_a(&resolver, vec![b"example", b"com"])?;
+        assert_eq!(ip, Ipv4Addr::new(10, 0, 0, 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff_waits_out_delayed_answer() -> Result<()> {
+        // the first attempt times out at 50ms, the retry waits 100ms
+        let addr = flaky_upstream(0, Duration::from_millis(75))?;
+        let resolver = Resolver::new(&[addr], options())?;
+
+        let (_, ip) = resolve_a(&resolver, vec![b"example", b"com"])?;
+        assert_eq!(ip, Ipv4Addr::new(10, 0, 0, 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gives_up_after_retries() -> Result<()> {
+        let addr = flaky_upstream(usize::MAX, Duration::ZERO)?;
+        let resolver = Resolver::new(&[addr], options())?;
+
+        let start = Instant::now();
+        assert!(resolve_a(&resolver, vec![b"example", b"com"]).is_err());
+        // 50 + 100 + 200ms
+        assert!(start.elapsed() >= Duration::from_millis(350));
+
+        Ok(())
+    }
+
+    // An upstream that answers every query with a burst of spoofed responses
+    // before the genuine one, or only the spoofed ones if `genuine` is false
+    fn spoofed_upstream(genuine: bool) -> Result<SocketAddr> {
+        let other = UdpSocket::bind("127.0.0.1:0")?;
+
+        mock_upstream(move |request, source| {
+            let spoofed = Ipv4Addr::new(6, 6, 6, 6);
+            let with_question = |change: fn(&mut Query)| {
+                let mut query = request.queries[0].clone();
+                change(&mut query);
+                let mut question = response(request, vec![]);
+                question.queries = vec![query];
+                answer(&question, spoofed)
+            };
+
+            let mut wrong_id = answer(request, spoofed);
+            wrong_id[..2].copy_from_slice(&request.id.wrapping_add(1).to_be_bytes());
+            let wrong_question = with_question(|q| q.name = vec![b"evil", b"com"]);
+            let wrong_type = with_question(|q| q.qtype = RRType::AAAA as u16);
+            let mut not_response = answer(request, spoofed);
+            not_response[2] &= 0b0111_1111;
+
+            // right response, wrong source address
+            other.send_to(&answer(request, spoofed), source).unwrap();
+
+            let mut responses = vec![wrong_id, wrong_question, wrong_type, not_response];
+            if genuine {
+                responses.push(answer(request, Ipv4Addr::new(10, 0, 0, 1)));
+            }
+            responses
+        })
+    }
+
+    #[test]
+    fn test_new_source_port_per_query() -> Result<()> {
+        let (sender, ports) = mpsc::channel();
+        let addr = mock_upstream(move |request, source| {
+            sender.send(source.port()).unwrap();
+            vec![answer(request, Ipv4Addr::new(10, 0, 0, 1))]
+        })?;
+        let resolver = Resolver::new(&[addr], options())?;
+
+        for _ in 0..2 {
+            resolve_a(&resolver, vec![b"example", b"com"])?;
+        }
+        assert_ne!(ports.recv()?, ports.recv()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_discards_mismatched_responses() -> Result<()> {
+        let addr = spoofed_upstream(true)?;
+        let resolver = Resolver::new(&[addr], options())?;
+
+        let (_, ip) = resolve_a(&resolver, vec![b"example", b"com"])?;
+        assert_eq!(ip, Ipv4Addr::new(10, 0, 0, 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_mismatched_responses_time_out() -> Result<()> {
+        let addr = spoofed_upstream(false)?;
+        let resolver = Resolver::new(&[addr], options())?;
+
+        assert!(resolve_a(&resolver, vec![b"example", b"com"]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_matches_case_insensitive_question() {
+        let flags = Flags {
+            qr: 0,
+            opcode: 0,
+            aa: 0,
+            tc: 0,
+            rd: 0,
+            ra: 0,
+            rcode: 0,
+        };
+        let question = |name| Query {
+            name,
+            qtype: RRType::A as u16,
+            qclass: RRClass::IN as u16,
+        };
+        let req = DNSHdr::new(5, flags, vec![question(vec![b"Example", b"COM"])], vec![]);
+        let response = DNSHdr::new(
+            5,
+            Flags { qr: 1, ..flags },
+            vec![question(vec![b"example", b"com"])],
+            vec![],
+        );
+
+        assert!(matches(&req, &response.to_bytes()));
+        assert!(!matches(&req, &req.to_bytes()));
+        assert!(!matches(&req, &[0, 5]));
+    }
+
+    // An upstream that answers NXDOMAIN with an SOA in the authority section
+    fn nxdomain_upstream() -> Result<SocketAddr> {
+        mock_upstream(|request, _| {
+            let mut response = response(request, vec![]);
+            response.flags.rcode = RCode::NameError as u8;
+            response.authorities.push(Answer::new(
+                vec![b"example", b"com"],
+                RRClass::IN,
+                300,
+                RData::SOA {
+                    mname: vec![b"ns", b"example", b"com"],
+                    rname: vec![b"hostmaster", b"example", b"com"],
+                    serial: 1,
+                    refresh: 3600,
+                    retry: 600,
+                    expire: 86400,
+                    minimum: 60,
+                },
+            ));
+            vec![response.to_bytes().to_vec()]
+        })
+    }
+
+    #[test]
+    fn test_resolve_passes_rcode_through() -> Result<()> {
+        let addr = nxdomain_upstream()?;
+        let resolver = Resolver::new(&[addr], options())?;
+
+        let query = Query {
+            name: vec![b"missing", b"example", b"com"],
+            qtype: RRType::MX as u16,
+            qclass: RRClass::IN as u16,
+        };
+        let response = resolver.resolve(&query)?;
+        let response = DNSHdr::from_bytes(&response)?;
+        assert_eq!(response.rcode(), RCode::NameError as u16);
+        assert_eq!(response.queries[0].qtype, RRType::MX as u16);
+        assert_eq!(response.authorities.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failover() -> Result<()> {
+        let silent = flaky_upstream(usize::MAX, Duration::ZERO)?;
+        let upstreams = [silent, answering_upstream(1, Duration::ZERO)?];
+        let resolver = Resolver::new(&upstreams, with_policy(Policy::Failover))?;
+
+        assert_eq!(answered_by(&resolver)?, 1);
+        assert_eq!(resolver.upstreams.lock().unwrap()[0].failures, 1);
+
+        Ok(())
+    }
+
+    // An upstream that answers every query with rcode
+    fn failing_upstream(rcode: RCode) -> Result<SocketAddr> {
+        let rcode = rcode as u8;
+        mock_upstream(move |request, _| {
+            let mut response = response(request, vec![]);
+            response.flags.rcode = rcode;
+            vec![response.to_bytes().to_vec()]
+        })
+    }
+
+    #[test]
+    fn test_fails_over_on_servfail_and_refused() -> Result<()> {
+        let upstreams = [
+            failing_upstream(RCode::ServerFailure)?,
+            failing_upstream(RCode::Refused)?,
+            answering_upstream(3, Duration::ZERO)?,
+        ];
+        let resolver = Resolver::new(&upstreams, with_policy(Policy::Failover))?;
+
+        assert_eq!(answered_by(&resolver)?, 3);
+        let failures = |resolver: &Resolver| {
+            let upstreams = resolver.upstreams.lock().unwrap();
+            upstreams.iter().map(|u| u.failures).collect::<Vec<_>>()
+        };
+        assert_eq!(failures(&resolver), vec![1, 1, 0]);
+
+        // with nobody better to ask the last such answer is passed on
+        let resolver = Resolver::new(&upstreams[..2], with_policy(Policy::Failover))?;
+        let response = resolver.resolve(&Query {
+            name: vec![b"example", b"com"],
+            qtype: RRType::A as u16,
+            qclass: RRClass::IN as u16,
+        })?;
+        assert_eq!(
+            DNSHdr::from_bytes(&response)?.rcode(),
+            RCode::Refused as u16
+        );
+        assert_eq!(failures(&resolver), vec![1, 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_robin() -> Result<()> {
+        let upstreams = (1..=3)
+            .map(|i| answering_upstream(i, Duration::ZERO))
+            .collect::<Result<Vec<_>>>()?;
+        let resolver = Resolver::new(&upstreams, with_policy(Policy::RoundRobin))?;
+
+        let answers = (0..4)
+            .map(|_| answered_by(&resolver))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(answers, vec![1, 2, 3, 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_random() -> Result<()> {
+        let upstreams = (1..=2)
+            .map(|i| answering_upstream(i, Duration::ZERO))
+            .collect::<Result<Vec<_>>>()?;
+        let resolver = Resolver::new(&upstreams, with_policy(Policy::Random))?;
+
+        let answers = (0..32)
+            .map(|_| answered_by(&resolver))
+            .collect::<Result<Vec<_>>>()?;
+        assert!(answers.contains(&1) && answers.contains(&2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fastest() -> Result<()> {
+        let upstreams = [
+            answering_upstream(1, Duration::from_millis(30))?,
+            answering_upstream(2, Duration::ZERO)?,
+        ];
+        let resolver = Resolver::new(&upstreams, with_policy(Policy::Fastest))?;
+
+        // both are measured first, then the fast one keeps winning
+        let answers = (0..5)
+            .map(|_| answered_by(&resolver))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(answers, vec![1, 2, 2, 2, 2]);
+        let upstreams = resolver.upstreams.lock().unwrap();
+        assert!(upstreams[0].srtt > upstreams[1].srtt);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_marks_down_and_probes() -> Result<()> {
+        let silent = flaky_upstream(usize::MAX, Duration::ZERO)?;
+        let upstreams = [silent, answering_upstream(2, Duration::ZERO)?];
+        let resolver = Resolver::new(
+            &upstreams,
+            ResolverOptions {
+                timeout: Duration::from_millis(20),
+                retries: 0,
+                max_failures: 2,
+                down_time: Duration::from_millis(200),
+                ..ResolverOptions::default()
+            },
+        )?;
+
+        for _ in 0..2 {
+            assert_eq!(answered_by(&resolver)?, 2);
+        }
+        assert!(!resolver.upstreams.lock().unwrap()[0].is_up(Instant::now()));
+
+        // skipped while down
+        assert_eq!(answered_by(&resolver)?, 2);
+        assert_eq!(resolver.upstreams.lock().unwrap()[0].failures, 2);
+
+        // probed again once the down time is over
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(answered_by(&resolver)?, 2);
+        assert_eq!(resolver.upstreams.lock().unwrap()[0].failures, 3);
+        assert!(!resolver.upstreams.lock().unwrap()[0].is_up(Instant::now()));
+
+        // with every upstream down they are still tried
+        let silent = flaky_upstream(usize::MAX, Duration::ZERO)?;
+        let resolver = Resolver::new(&[silent], resolver.client.options)?;
+        for _ in 0..3 {
+            assert!(answered_by(&resolver).is_err());
+        }
+        assert_eq!(resolver.upstreams.lock().unwrap()[0].failures, 3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,23 @@
+use std::io::{Read, Result, Write};
+
+/*
+DNS messages over a stream (RFC 1035 4.2.2) are prefixed with their length as
+a two byte integer in network order.
+*/
+pub fn read_message(stream: &mut impl Read) -> Result<Vec<u8>> {
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+
+    let mut msg = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut msg)?;
+
+    Ok(msg)
+}
+
+pub fn write_message(stream: &mut impl Write, msg: &[u8]) -> Result<()> {
+    let mut buf = Vec::with_capacity(2 + msg.len());
+    buf.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+    buf.extend_from_slice(msg);
+
+    stream.write_all(&buf)
+}
@@ -0,0 +1,76 @@
+use crate::dns_hdr::{Answer, DNSHdr, Flags, EDNS_UDP_SIZE};
+use crate::dns_server::Upstream;
+use crate::name::DomainName;
+use crate::resolver::{Resolver, ResolverOptions};
+use anyhow::Result;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+pub fn name(s: &str) -> DomainName {
+    s.parse().unwrap()
+}
+
+/*
+A fake name server for tests. It listens for UDP queries on addr and sends
+back whatever messages handler returns for each one, in order; returning none
+drops the query. The handler also gets the address the query came from.
+*/
+pub fn mock_upstream_at(
+    addr: &str,
+    mut handler: impl FnMut(&DNSHdr, SocketAddr) -> Vec<Vec<u8>> + Send + 'static,
+) -> Result<SocketAddr> {
+    let socket = UdpSocket::bind(addr)?;
+    let addr = socket.local_addr()?;
+
+    thread::spawn(move || {
+        let mut buf = [0; EDNS_UDP_SIZE as usize];
+        while let Ok((size, source)) = socket.recv_from(&mut buf) {
+            let request = DNSHdr::from_bytes(&buf[..size]).unwrap();
+            for response in handler(&request, source) {
+                let _ = socket.send_to(&response, source);
+            }
+        }
+    });
+
+    Ok(addr)
+}
+
+// A fake name server on any free local port
+pub fn mock_upstream(
+    handler: impl FnMut(&DNSHdr, SocketAddr) -> Vec<Vec<u8>> + Send + 'static,
+) -> Result<SocketAddr> {
+    mock_upstream_at("127.0.0.1:0", handler)
+}
+
+// The response to request with the given answers
+pub fn response<'a>(request: &DNSHdr<'a>, answers: Vec<Answer<'a>>) -> DNSHdr<'a> {
+    DNSHdr::new(
+        request.id,
+        Flags {
+            qr: 1,
+            ..request.flags
+        },
+        request.queries.clone(),
+        answers,
+    )
+}
+
+// A resolver whose only upstream never answers, giving up after 20ms
+pub fn silent_resolver() -> Result<Resolver> {
+    let upstream = mock_upstream(|_, _| vec![])?;
+    Resolver::new(
+        &[upstream],
+        ResolverOptions {
+            timeout: Duration::from_millis(20),
+            retries: 0,
+            ..ResolverOptions::default()
+        },
+    )
+}
+
+// An upstream for a server that fails every question, so that whatever is
+// still answered comes from our zones or the cache
+pub fn silent_upstream() -> Result<Upstream> {
+    Ok(Upstream::Forward(silent_resolver()?))
+}
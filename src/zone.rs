@@ -0,0 +1,715 @@
+use crate::dns_hdr::{NameCompression, RData};
+use crate::name::{decode_escape, DomainName};
+use bytes::BytesMut;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+
+// $INCLUDE chains deeper than this are taken to be a loop
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+// A record in the local database, with its RDATA kept in wire format
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub rtype: u16,
+    pub ttl: u32,
+    pub rdata: Vec<u8>,
+}
+
+impl Record {
+    pub fn new(ttl: u32, rdata: &RData) -> Self {
+        let mut buf = BytesMut::new();
+        rdata.to_bytes(&mut buf, &mut NameCompression::default());
+        Record {
+            rtype: rdata.rtype(),
+            ttl,
+            rdata: buf.to_vec(),
+        }
+    }
+
+    // Names in the RDATA can only point back into the RDATA itself
+    pub fn rdata(&self) -> RData<'_> {
+        RData::from_bytes(self.rtype, &self.rdata, &self.rdata).expect("stored RDATA is valid")
+    }
+
+    // How long a negative answer backed by this SOA may be kept, the smaller
+    // of its TTL and the minimum field (RFC 2308 5)
+    pub fn negative_ttl(&self) -> u32 {
+        match self.rdata() {
+            RData::SOA { minimum, .. } => self.ttl.min(minimum),
+            _ => self.ttl,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ZoneError {
+    #[error("{}: {source}", file.display())]
+    Io {
+        file: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("{}:{line}: {msg}", file.display())]
+    Syntax {
+        file: PathBuf,
+        line: usize,
+        msg: String,
+    },
+    #[error("zone {origin}: {msg}")]
+    Invalid { origin: String, msg: String },
+}
+
+// Records with their owner names
+pub type ZoneRecords = Vec<(DomainName, Record)>;
+
+/*
+Loads an RFC 1035 master file (section 5). Relative names are completed with
+origin until a $ORIGIN changes it, records without a TTL take the $TTL
+default, or else the last TTL given, and $INCLUDE files are read relative to
+the directory of the file that includes them.
+*/
+pub fn load(path: impl AsRef<Path>, origin: &DomainName) -> Result<ZoneRecords, ZoneError> {
+    let src = read(path.as_ref())?;
+    parse(&src, path, origin)
+}
+
+// Like load, with the master file already read; file is only used to report
+// errors and to find $INCLUDE files
+pub fn parse(
+    src: &str,
+    file: impl AsRef<Path>,
+    origin: &DomainName,
+) -> Result<ZoneRecords, ZoneError> {
+    let mut records = vec![];
+    Parser::new(file.as_ref(), origin).parse(src, &mut records, 0)?;
+    Ok(records)
+}
+
+fn read(file: &Path) -> Result<String, ZoneError> {
+    fs::read_to_string(file).map_err(|source| ZoneError::Io {
+        file: file.to_path_buf(),
+        source,
+    })
+}
+
+// A logical line: parentheses may spread one entry over several lines
+#[derive(Debug, Default)]
+struct Entry {
+    line: usize,
+    // starts with a blank, so the owner is the previous one
+    blank_owner: bool,
+    tokens: Vec<String>,
+}
+
+fn tokenize(src: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut entries = vec![];
+    let mut entry = Entry::default();
+    let mut depth = 0;
+
+    for (n, line) in src.lines().enumerate() {
+        let n = n + 1;
+        if depth == 0 {
+            entry = Entry {
+                line: n,
+                blank_owner: line.starts_with([' ', '\t']),
+                tokens: vec![],
+            };
+        }
+
+        let mut token: Option<String> = None;
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                ';' => break,
+                ' ' | '\t' | '(' | ')' => {
+                    entry.tokens.extend(token.take());
+                    match c {
+                        '(' => depth += 1,
+                        ')' if depth == 0 => return Err((n, "unbalanced ')'".to_string())),
+                        ')' => depth -= 1,
+                        _ => {}
+                    }
+                }
+                '"' => {
+                    entry.tokens.extend(token.take());
+                    let mut quoted = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            // escapes are decoded later, only skip over them
+                            Some('\\') => {
+                                quoted.push('\\');
+                                quoted.extend(chars.next());
+                            }
+                            Some(c) => quoted.push(c),
+                            None => return Err((n, "unterminated string".to_string())),
+                        }
+                    }
+                    entry.tokens.push(quoted);
+                }
+                c => {
+                    let token = token.get_or_insert_with(String::new);
+                    token.push(c);
+                    if c == '\\' {
+                        token.extend(chars.next());
+                    }
+                }
+            }
+        }
+        entry.tokens.extend(token);
+
+        if depth == 0 && !entry.tokens.is_empty() {
+            entries.push(std::mem::take(&mut entry));
+        }
+    }
+
+    if depth > 0 {
+        return Err((entry.line, "unbalanced '('".to_string()));
+    }
+    Ok(entries)
+}
+
+// Decodes the escapes in a token, see decode_escape
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let bytes = text.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => {
+                let (b, len) = decode_escape(&bytes[i + 1..])
+                    .ok_or_else(|| format!("invalid escape in {text:?}"))?;
+                out.push(b);
+                i += len + 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+fn char_string(text: &str) -> Result<Vec<u8>, String> {
+    let s = unescape(text)?;
+    if s.len() > u8::MAX as usize {
+        return Err(format!("character string longer than {} bytes", u8::MAX));
+    }
+    Ok(s)
+}
+
+fn number<T: FromStr>(text: &str, what: &str) -> Result<T, String> {
+    text.parse().map_err(|_| format!("invalid {what} {text:?}"))
+}
+
+// Seconds, or a BIND style duration such as 1h30m
+fn ttl(text: &str) -> Result<u32, String> {
+    if let Ok(ttl) = text.parse() {
+        return Ok(ttl);
+    }
+
+    let invalid = || format!("invalid TTL {text:?}");
+    let mut total: u32 = 0;
+    let mut digits = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let value: u32 = digits.parse().map_err(|_| invalid())?;
+        total = value
+            .checked_mul(unit)
+            .and_then(|v| total.checked_add(v))
+            .ok_or_else(invalid)?;
+        digits.clear();
+    }
+
+    if !digits.is_empty() {
+        return Err(invalid());
+    }
+    Ok(total)
+}
+
+struct Parser {
+    file: PathBuf,
+    origin: DomainName,
+    default_ttl: Option<u32>,
+    last_ttl: Option<u32>,
+    last_owner: Option<DomainName>,
+}
+
+impl Parser {
+    fn new(file: &Path, origin: &DomainName) -> Self {
+        Parser {
+            file: file.to_path_buf(),
+            origin: origin.clone(),
+            default_ttl: None,
+            last_ttl: None,
+            last_owner: None,
+        }
+    }
+
+    fn error(&self, line: usize, msg: impl Into<String>) -> ZoneError {
+        ZoneError::Syntax {
+            file: self.file.clone(),
+            line,
+            msg: msg.into(),
+        }
+    }
+
+    fn load(&mut self, records: &mut ZoneRecords, depth: usize) -> Result<(), ZoneError> {
+        let src = read(&self.file)?;
+        self.parse(&src, records, depth)
+    }
+
+    fn parse(
+        &mut self,
+        src: &str,
+        records: &mut ZoneRecords,
+        depth: usize,
+    ) -> Result<(), ZoneError> {
+        let entries = tokenize(src).map_err(|(line, msg)| self.error(line, msg))?;
+
+        for entry in entries {
+            let args = entry.tokens[1..]
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            let directive = (!entry.blank_owner).then_some(entry.tokens[0].as_str());
+
+            match (directive, args.as_slice()) {
+                (Some("$ORIGIN"), [origin]) => {
+                    self.origin = self.name(origin).map_err(|e| self.error(entry.line, e))?;
+                }
+                (Some("$TTL"), [default]) => {
+                    self.default_ttl = Some(ttl(default).map_err(|e| self.error(entry.line, e))?);
+                }
+                (Some("$INCLUDE"), [file, origin @ ..]) if origin.len() <= 1 => {
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        return Err(self.error(entry.line, "$INCLUDE nested too deeply"));
+                    }
+
+                    // the included file starts with our state, but whatever
+                    // it changes does not carry over back here (RFC 1035 5.1)
+                    let mut include = Parser {
+                        file: self.file.parent().unwrap_or(Path::new("")).join(file),
+                        origin: self.origin.clone(),
+                        default_ttl: self.default_ttl,
+                        last_ttl: self.last_ttl,
+                        last_owner: None,
+                    };
+                    if let [origin] = origin {
+                        include.origin =
+                            self.name(origin).map_err(|e| self.error(entry.line, e))?;
+                    }
+                    include.load(records, depth + 1)?;
+                }
+                (Some(directive), _) if directive.starts_with('$') => {
+                    return Err(self.error(entry.line, format!("malformed {directive}")));
+                }
+                _ => {
+                    let record = self.record(&entry).map_err(|e| self.error(entry.line, e))?;
+                    records.push(record);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Completes a name relative to the current origin
+    fn name(&self, text: &str) -> Result<DomainName, String> {
+        match text {
+            "@" => Ok(self.origin.clone()),
+            text => DomainName::parse_relative(text, &self.origin).map_err(|e| e.to_string()),
+        }
+    }
+
+    // [<owner>] [<TTL>] [<class>] <type> <RDATA>, with TTL and class in
+    // either order
+    fn record(&mut self, entry: &Entry) -> Result<(DomainName, Record), String> {
+        let mut tokens = entry.tokens.iter();
+        let owner = match entry.blank_owner {
+            true => self.last_owner.clone().ok_or("no previous owner name")?,
+            false => self.name(tokens.next().expect("entries are not empty"))?,
+        };
+        self.last_owner = Some(owner.clone());
+
+        let mut ttl_field = None;
+        let rtype = loop {
+            let token = tokens.next().ok_or("missing record type")?;
+            match token.to_ascii_uppercase().as_str() {
+                "IN" => {}
+                "CS" | "CH" | "HS" => return Err(format!("unsupported class {token}")),
+                _ if token.starts_with(|c: char| c.is_ascii_digit()) => {
+                    ttl_field = Some(ttl(token)?);
+                }
+                rtype => break rtype.to_string(),
+            }
+        };
+
+        let ttl = match ttl_field {
+            Some(ttl) => {
+                self.last_ttl = Some(ttl);
+                ttl
+            }
+            None => self
+                .default_ttl
+                .or(self.last_ttl)
+                .ok_or("no TTL given and no $TTL default")?,
+        };
+
+        let record = self.rdata(&rtype, tokens.as_slice(), ttl)?;
+        Ok((owner, record))
+    }
+
+    fn rdata(&self, rtype: &str, tokens: &[String], ttl: u32) -> Result<Record, String> {
+        let args = tokens.iter().map(String::as_str).collect::<Vec<_>>();
+        let fields = |n: usize| match args.len() == n {
+            true => Ok(()),
+            false => Err(format!("{rtype} takes {n} fields, found {}", args.len())),
+        };
+
+        let record = match rtype {
+            "A" => {
+                fields(1)?;
+                Record::new(ttl, &RData::A(number(args[0], "IPv4 address")?))
+            }
+            "AAAA" => {
+                fields(1)?;
+                Record::new(ttl, &RData::AAAA(number(args[0], "IPv6 address")?))
+            }
+            "NS" | "CNAME" | "PTR" => {
+                fields(1)?;
+                let name = self.name(args[0])?;
+                let name = name.labels();
+                let rdata = match rtype {
+                    "NS" => RData::NS(name),
+                    "CNAME" => RData::CNAME(name),
+                    _ => RData::PTR(name),
+                };
+                Record::new(ttl, &rdata)
+            }
+            "SOA" => {
+                fields(7)?;
+                let (mname, rname) = (self.name(args[0])?, self.name(args[1])?);
+                Record::new(
+                    ttl,
+                    &RData::SOA {
+                        mname: mname.labels(),
+                        rname: rname.labels(),
+                        serial: number(args[2], "serial")?,
+                        refresh: self::ttl(args[3])?,
+                        retry: self::ttl(args[4])?,
+                        expire: self::ttl(args[5])?,
+                        minimum: self::ttl(args[6])?,
+                    },
+                )
+            }
+            "MX" => {
+                fields(2)?;
+                let exchange = self.name(args[1])?;
+                Record::new(
+                    ttl,
+                    &RData::MX {
+                        preference: number(args[0], "preference")?,
+                        exchange: exchange.labels(),
+                    },
+                )
+            }
+            "TXT" => {
+                if args.is_empty() {
+                    return Err("TXT takes at least one string".to_string());
+                }
+                let strings = args
+                    .iter()
+                    .map(|s| char_string(s))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Record::new(
+                    ttl,
+                    &RData::TXT(strings.iter().map(Vec::as_slice).collect()),
+                )
+            }
+            "SRV" => {
+                fields(4)?;
+                let target = self.name(args[3])?;
+                Record::new(
+                    ttl,
+                    &RData::SRV {
+                        priority: number(args[0], "priority")?,
+                        weight: number(args[1], "weight")?,
+                        port: number(args[2], "port")?,
+                        target: target.labels(),
+                    },
+                )
+            }
+            "CAA" => {
+                fields(3)?;
+                let tag = char_string(args[1])?;
+                if tag.is_empty() || !tag.iter().all(u8::is_ascii_alphanumeric) {
+                    return Err(format!("invalid CAA tag {:?}", args[1]));
+                }
+                // the value is not a <character-string>, it may be longer
+                let value = unescape(args[2])?;
+                Record::new(
+                    ttl,
+                    &RData::CAA {
+                        flags: number(args[0], "flags")?,
+                        tag: &tag,
+                        value: &value,
+                    },
+                )
+            }
+            _ => return Err(format!("unsupported record type {rtype}")),
+        };
+
+        Ok(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_hdr::RRType;
+    use crate::testing::name;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn rdata<'a>(records: &'a ZoneRecords, owner: &str, rtype: RRType) -> Vec<RData<'a>> {
+        records
+            .iter()
+            .filter(|(n, r)| *n == name(owner) && r.rtype == rtype as u16)
+            .map(|(_, r)| r.rdata())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_zone() -> Result<(), ZoneError> {
+        let src = r#"
+$ORIGIN example.com.
+$TTL 1h
+@   IN  SOA ns1 hostmaster.example.com. (
+            2024010101 ; serial
+            3600       ; refresh
+            600        ; retry
+            1w         ; expire
+            300 )      ; minimum
+        NS  ns1
+        NS  ns2.example.net.
+        MX  10 mail
+www 300 IN A 192.0.2.1
+        IN 60 AAAA 2001:db8::1
+mail    A   192.0.2.25
+txt     TXT "v=spf1 -all" "a \"quoted\" ; string" plain\032text
+_sip._tcp SRV 10 60 5060 sip
+        CAA 0 issue "letsencrypt.org"
+alias   CNAME www
+$ORIGIN 2.0.192.in-addr.arpa.
+1       PTR www.example.com.
+"#;
+        let records = parse(src, "example.com.zone", &name("example.com"))?;
+        assert_eq!(records.len(), 12);
+
+        let (_, soa) = &records[0];
+        assert_eq!(soa.ttl, 3600);
+        assert_eq!(
+            rdata(&records, "example.com", RRType::SOA),
+            vec![RData::SOA {
+                mname: vec![b"ns1", b"example", b"com"],
+                rname: vec![b"hostmaster", b"example", b"com"],
+                serial: 2024010101,
+                refresh: 3600,
+                retry: 600,
+                expire: 604800,
+                minimum: 300,
+            }]
+        );
+        assert_eq!(
+            rdata(&records, "example.com", RRType::NS),
+            vec![
+                RData::NS(vec![b"ns1", b"example", b"com"]),
+                RData::NS(vec![b"ns2", b"example", b"net"]),
+            ]
+        );
+        assert_eq!(
+            rdata(&records, "example.com", RRType::MX),
+            vec![RData::MX {
+                preference: 10,
+                exchange: vec![b"mail", b"example", b"com"],
+            }]
+        );
+
+        let www = records
+            .iter()
+            .filter(|(n, _)| *n == name("www.example.com"))
+            .map(|(_, r)| (r.ttl, r.rdata()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            www,
+            vec![
+                (300, RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+                (60, RData::AAAA("2001:db8::1".parse::<Ipv6Addr>().unwrap())),
+            ]
+        );
+
+        assert_eq!(
+            rdata(&records, "txt.example.com", RRType::TXT),
+            vec![RData::TXT(vec![
+                b"v=spf1 -all",
+                b"a \"quoted\" ; string",
+                b"plain text",
+            ])]
+        );
+        assert_eq!(
+            rdata(&records, "_sip._tcp.example.com", RRType::SRV),
+            vec![RData::SRV {
+                priority: 10,
+                weight: 60,
+                port: 5060,
+                target: vec![b"sip", b"example", b"com"],
+            }]
+        );
+        assert_eq!(
+            rdata(&records, "_sip._tcp.example.com", RRType::CAA),
+            vec![RData::CAA {
+                flags: 0,
+                tag: b"issue",
+                value: b"letsencrypt.org",
+            }]
+        );
+        assert_eq!(
+            rdata(&records, "alias.example.com", RRType::CNAME),
+            vec![RData::CNAME(vec![b"www", b"example", b"com"])]
+        );
+        assert_eq!(
+            rdata(&records, "1.2.0.192.in-addr.arpa", RRType::PTR),
+            vec![RData::PTR(vec![b"www", b"example", b"com"])]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ttl_defaults() -> Result<(), ZoneError> {
+        // without $TTL the last explicit TTL carries over
+        let records = parse(
+            "a 120 A 192.0.2.1\nb A 192.0.2.2\n",
+            "t.zone",
+            &name("example"),
+        )?;
+        assert_eq!(records[1].1.ttl, 120);
+
+        let records = parse(
+            "$TTL 1d\na 120 A 192.0.2.1\nb A 192.0.2.2\n",
+            "t.zone",
+            &name("."),
+        )?;
+        assert_eq!(records[0].0, name("a"));
+        assert_eq!(records[1].1.ttl, 86400);
+
+        assert_eq!(ttl("1h30m"), Ok(5400));
+        assert!(ttl("1x").is_err());
+        assert!(ttl("1h30").is_err());
+
+        Ok(())
+    }
+
+    fn syntax_error(src: &str) -> (usize, String) {
+        match parse(src, "bad.zone", &name("example.com")) {
+            Err(ZoneError::Syntax { file, line, msg }) => {
+                assert_eq!(file, Path::new("bad.zone"));
+                (line, msg)
+            }
+            other => panic!("expected a syntax error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(syntax_error("a A 192.0.2.1\n").0, 1);
+        assert_eq!(syntax_error("$TTL 60\n\na A 192.0.2.300\n").0, 3);
+        assert_eq!(syntax_error("$TTL 60\n a A 192.0.2.1\n").0, 2);
+        assert_eq!(syntax_error("$TTL 60\na ( A\n192.0.2.1\n").0, 2);
+        assert_eq!(syntax_error("$TTL 60\na A 192.0.2.1 )\n").0, 2);
+        assert_eq!(syntax_error("$TTL 60\na TXT \"open\n").0, 2);
+        assert_eq!(syntax_error("$TTL 60\na MX mail\n").0, 2);
+        assert_eq!(syntax_error("$TTL 60\na CH TXT x\n").0, 2);
+        assert_eq!(syntax_error("$TTL 60\na HINFO x y\n").0, 2);
+        assert_eq!(syntax_error("$TTL 60\na..b A 192.0.2.1\n").0, 2);
+        assert_eq!(syntax_error("$TTL 60\n$ORIGIN\n").0, 2);
+
+        let (line, msg) = syntax_error("$TTL 60\n\n\nwww A not-an-address\n");
+        assert_eq!(line, 4);
+        assert_eq!(msg, "invalid IPv4 address \"not-an-address\"");
+        assert_eq!(
+            parse("www A 1\n", "bad.zone", &name("example.com"))
+                .unwrap_err()
+                .to_string(),
+            "bad.zone:1: no TTL given and no $TTL default"
+        );
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zone-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_include() -> Result<(), ZoneError> {
+        let dir = temp_dir("include");
+        fs::write(
+            dir.join("main.zone"),
+            "$TTL 60\n$INCLUDE hosts.zone\n$INCLUDE hosts.zone sub\nmain A 192.0.2.1\n",
+        )
+        .unwrap();
+        fs::write(dir.join("hosts.zone"), "$ORIGIN other.\nhost A 192.0.2.2\n").unwrap();
+
+        let records = load(dir.join("main.zone"), &name("example.com."))?;
+        let names = records
+            .iter()
+            .map(|(n, _)| n.to_string())
+            .collect::<Vec<_>>();
+        // the $ORIGIN in the included file does not leak back out
+        assert_eq!(
+            names,
+            vec!["host.other.", "host.other.", "main.example.com."]
+        );
+
+        fs::write(dir.join("main.zone"), "$TTL 60\n$INCLUDE sub/broken.zone\n").unwrap();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/broken.zone"), "ok A 192.0.2.1\nbad A\n").unwrap();
+        match load(dir.join("main.zone"), &name("example.com")) {
+            Err(ZoneError::Syntax { file, line, .. }) => {
+                assert_eq!(file, dir.join("sub/broken.zone"));
+                assert_eq!(line, 2);
+            }
+            other => panic!("expected a syntax error, got {other:?}"),
+        }
+
+        fs::write(dir.join("main.zone"), "$INCLUDE main.zone\n").unwrap();
+        assert!(matches!(
+            load(dir.join("main.zone"), &name("example.com")),
+            Err(ZoneError::Syntax { .. })
+        ));
+
+        assert!(matches!(
+            load(dir.join("missing.zone"), &name("example.com")),
+            Err(ZoneError::Io { .. })
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+        Ok(())
+    }
+}